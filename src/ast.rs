@@ -1,3 +1,7 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
 /// A whole program: zero or more functions, then zero or more global statements.
 #[derive(Debug)]
 pub struct Program {
@@ -5,36 +9,97 @@ pub struct Program {
     pub statements: Vec<Statement>,
 }
 
-/// A function declaration: name, parameter list, and a body of statements.
-#[derive(Debug)]
+/// A function declaration: name, type parameters, parameter list, optional
+/// return type annotation, and a body of statements.
+#[derive(Debug, Clone)]
 pub struct Function {
     pub name: String,
-    pub params: Vec<String>,
+    pub line: usize,
+    pub type_params: Vec<String>,
+    pub params: Vec<Param>,
+    pub return_type: Option<Type>,
     pub body: Vec<Statement>,
 }
 
+/// A function parameter: a name or destructuring pattern, with an optional type annotation.
+#[derive(Debug, Clone)]
+pub struct Param {
+    pub pattern: Pattern,
+    pub ty: Option<Type>,
+}
+
+/// What a `let` or a parameter binds its value to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pattern {
+    Ident(String),
+    /// `_`, which ignores the value.
+    Wildcard,
+    /// `(a, b, …)`
+    Tuple(Vec<Pattern>),
+    /// `[a, b, …]`, which also requires the array to have exactly that many elements.
+    Array(Vec<Pattern>),
+}
+
+impl Pattern {
+    /// The bound name when the pattern is a plain identifier.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Pattern::Ident(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Every name the pattern binds, left to right.
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Pattern::Ident(name) => vec![name],
+            Pattern::Wildcard => Vec::new(),
+            Pattern::Tuple(elems) | Pattern::Array(elems) => {
+                elems.iter().flat_map(|elem| elem.names()).collect()
+            }
+        }
+    }
+}
+
 /// All the statements our language supports.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Statement {
+    /// `var name: ty = expr;`, where `ty` is filled in by the type checker if omitted.
+    /// The initializer may be left out, in which case every path must assign to the
+    /// variable before reading it.
     VarDecl {
         name: String,
-        expr: Expr,
+        ty: Option<Type>,
+        expr: Option<Expr>,
+        line: usize,
     },
+    /// Like `VarDecl`, but the binding cannot be assigned to.
     LetDecl {
         name: String,
+        ty: Option<Type>,
+        expr: Expr,
+        line: usize,
+    },
+    /// `let (a, [b, _]) = expr;`
+    LetPattern {
+        pattern: Pattern,
         expr: Expr,
+        line: usize,
     },
     Assign {
         name: String,
         expr: Expr,
+        line: usize,
     },
     IndexedAssign {
         array: Box<Expr>,
         index: Box<Expr>,
         expr: Box<Expr>,
+        line: usize,
     },
     Return {
         expr: Expr,
+        line: usize,
     },
     Print {
         expr: Expr,
@@ -43,49 +108,83 @@ pub enum Statement {
         cond: Expr,
         then_branch: Vec<Statement>,
         else_branch: Option<Vec<Statement>>,
+        line: usize,
     },
     While {
         cond: Expr,
         body: Vec<Statement>,
+        line: usize,
     },
     ExprStmt(Expr),
 }
 
+/// An expression, and the type the type checker gave it.
+#[derive(Debug, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    /// `None` until the type checker fills it in.
+    pub ty: Option<Type>,
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind) -> Self {
+        Expr { kind, ty: None }
+    }
+}
+
 /// All the expression forms we support.
-#[derive(Debug)]
-pub enum Expr {
-    Number(i64),
+#[derive(Debug, Clone)]
+pub enum ExprKind {
+    /// An integer literal, whose type the type checker fills in. Wide enough for
+    /// every value of every integer type.
+    Number(i128, IntKind),
     Bool(bool),
     StrLiteral(String),
     Variable(String),
     Unary {
         op: UnOp,
         expr: Box<Expr>,
+        line: usize,
     },
     Binary {
         op: BinOp,
         left: Box<Expr>,
         right: Box<Expr>,
+        line: usize,
     },
     Call {
         name: String,
         args: Vec<Expr>,
     },
     ArrayLiteral(Vec<Expr>),
+    Tuple(Vec<Expr>),
     Index {
         array: Box<Expr>,
         index: Box<Expr>,
+        line: usize,
     },
     Length {
         array: Box<Expr>,
     },
+    MethodCall {
+        receiver: Box<Expr>,
+        method: String,
+        args: Vec<Expr>,
+        line: usize,
+    },
+    /// `expr as u8`, which sign or zero extends, or truncates, an integer or bool.
+    Cast {
+        expr: Box<Expr>,
+        to: IntKind,
+    },
 }
 
-/// Unary operators: + and -.
+/// Unary operators: +, - and !.
 #[derive(Debug, Clone, Copy)]
 pub enum UnOp {
     Pos,
     Neg,
+    Not,
 }
 
 /// Binary operators.
@@ -102,4 +201,145 @@ pub enum BinOp {
     Ge,
     Eq,
     Ne,
+    /// `&&`, which only evaluates its right side if the left is true.
+    And,
+    /// `||`, which only evaluates its right side if the left is false.
+    Or,
+}
+
+/// The width and signedness of an integer type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntKind {
+    I8,
+    I16,
+    I32,
+    /// What `int` stands for.
+    I64,
+    U8,
+    U32,
+    U64,
+}
+
+impl IntKind {
+    pub fn from_name(name: &str) -> Option<IntKind> {
+        match name {
+            "i8" => Some(IntKind::I8),
+            "i16" => Some(IntKind::I16),
+            "i32" => Some(IntKind::I32),
+            "int" | "i64" => Some(IntKind::I64),
+            "u8" => Some(IntKind::U8),
+            "u32" => Some(IntKind::U32),
+            "u64" => Some(IntKind::U64),
+            _ => None,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            IntKind::I8 | IntKind::U8 => 8,
+            IntKind::I16 => 16,
+            IntKind::I32 | IntKind::U32 => 32,
+            IntKind::I64 | IntKind::U64 => 64,
+        }
+    }
+
+    pub fn signed(self) -> bool {
+        matches!(
+            self,
+            IntKind::I8 | IntKind::I16 | IntKind::I32 | IntKind::I64
+        )
+    }
+
+    /// The values the type can hold.
+    pub fn range(self) -> RangeInclusive<i128> {
+        let bits = self.bits();
+        if self.signed() {
+            -(1 << (bits - 1))..=(1 << (bits - 1)) - 1
+        } else {
+            0..=(1 << bits) - 1
+        }
+    }
+
+    /// `value` truncated to this type's width and read back as this type, the way
+    /// `as` converts.
+    pub fn wrap(self, value: i128) -> i128 {
+        let bits = self.bits();
+        let low = value & ((1 << bits) - 1);
+        if self.signed() && low >> (bits - 1) == 1 {
+            low - (1 << bits)
+        } else {
+            low
+        }
+    }
+}
+
+impl fmt::Display for IntKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            IntKind::I8 => "i8",
+            IntKind::I16 => "i16",
+            IntKind::I32 => "i32",
+            IntKind::I64 => "int",
+            IntKind::U8 => "u8",
+            IntKind::U32 => "u32",
+            IntKind::U64 => "u64",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The type of a value, as far as code generation is concerned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Int(IntKind),
+    Bool,
+    Array(Box<Type>),
+    /// A growable, heap-backed list created with `list()`.
+    List(Box<Type>),
+    /// An immutable, NUL-terminated string.
+    Str,
+    Tuple(Vec<Type>),
+    /// A type parameter of a generic function, replaced before code generation.
+    Param(String),
+}
+
+impl Type {
+    /// Plain `int`.
+    pub const INT: Type = Type::Int(IntKind::I64);
+
+    /// Replace type parameters using `bindings`.
+    pub fn substitute(&self, bindings: &HashMap<String, Type>) -> Type {
+        match self {
+            Type::Param(name) => bindings.get(name).cloned().unwrap_or_else(|| self.clone()),
+            Type::Array(elem) => Type::Array(Box::new(elem.substitute(bindings))),
+            Type::List(elem) => Type::List(Box::new(elem.substitute(bindings))),
+            Type::Tuple(elems) => {
+                Type::Tuple(elems.iter().map(|t| t.substitute(bindings)).collect())
+            }
+            Type::Int(_) | Type::Bool | Type::Str => self.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int(kind) => write!(f, "{}", kind),
+            Type::Bool => write!(f, "bool"),
+            Type::Array(elem) => write!(f, "[{}]", elem),
+            Type::List(elem) => write!(f, "list<{}>", elem),
+            Type::Str => write!(f, "string"),
+            Type::Tuple(elems) => {
+                write!(f, "(")?;
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", elem)?;
+                }
+                write!(f, ")")
+            }
+            Type::Param(name) => write!(f, "{}", name),
+        }
+    }
 }
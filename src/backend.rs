@@ -0,0 +1,247 @@
+use crate::error::CompileError;
+use inkwell::{
+    OptimizationLevel,
+    module::{FlagBehavior, Module},
+    passes::PassBuilderOptions,
+    targets::{
+        CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+    },
+};
+use std::path::Path;
+use std::process::Command;
+
+/// How hard to optimize, as chosen by `-O0` through `-O3` and `-Os`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OptLevel {
+    #[default]
+    O0,
+    O1,
+    O2,
+    O3,
+    /// Optimize for size.
+    Os,
+}
+
+impl OptLevel {
+    pub fn from_flag(flag: &str) -> Option<OptLevel> {
+        match flag {
+            "-O0" => Some(OptLevel::O0),
+            "-O1" => Some(OptLevel::O1),
+            "-O2" => Some(OptLevel::O2),
+            "-O3" => Some(OptLevel::O3),
+            "-Os" => Some(OptLevel::Os),
+            _ => None,
+        }
+    }
+
+    /// The new pass manager's standard pipeline for this level.
+    pub fn pipeline(self) -> &'static str {
+        match self {
+            OptLevel::O0 => "default<O0>",
+            OptLevel::O1 => "default<O1>",
+            OptLevel::O2 => "default<O2>",
+            OptLevel::O3 => "default<O3>",
+            OptLevel::Os => "default<Os>",
+        }
+    }
+
+    /// The matching level for instruction selection and the JIT.
+    pub fn codegen_level(self) -> OptimizationLevel {
+        match self {
+            OptLevel::O0 => OptimizationLevel::None,
+            OptLevel::O1 => OptimizationLevel::Less,
+            OptLevel::O2 | OptLevel::Os => OptimizationLevel::Default,
+            OptLevel::O3 => OptimizationLevel::Aggressive,
+        }
+    }
+}
+
+/// What `build` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Emit {
+    /// An executable, linked with the system's `cc`.
+    #[default]
+    Exe,
+    Obj,
+    Asm,
+}
+
+impl Emit {
+    pub fn from_name(name: &str) -> Option<Emit> {
+        match name {
+            "exe" => Some(Emit::Exe),
+            "obj" => Some(Emit::Obj),
+            "asm" => Some(Emit::Asm),
+            _ => None,
+        }
+    }
+
+    /// The extension of the file written, after the source file's stem.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Emit::Exe => "",
+            Emit::Obj => "o",
+            Emit::Asm => "s",
+        }
+    }
+
+    pub fn file_type(self) -> FileType {
+        match self {
+            Emit::Exe | Emit::Obj => FileType::Object,
+            Emit::Asm => FileType::Assembly,
+        }
+    }
+}
+
+/// A target machine for `triple`, or for the host when there is none. The module is
+/// also told which target it is compiled for.
+///
+/// Without a `cpu`, code is generated for the baseline CPU of the architecture, so
+/// that it runs on any machine of that kind; `native` asks for the host's own CPU.
+/// `features` otherwise default to the ones clang enables for a Linux triple.
+pub fn target_machine(
+    module: &Module,
+    triple: Option<&str>,
+    cpu: Option<&str>,
+    features: Option<&str>,
+    level: OptLevel,
+) -> Result<TargetMachine, CompileError> {
+    let config = InitializationConfig::default();
+    let triple = match triple {
+        None => {
+            Target::initialize_native(&config).map_err(|e| {
+                CompileError::Codegen(format!("Cannot initialize the host target: {}", e))
+            })?;
+            TargetMachine::get_default_triple()
+        }
+        Some(triple) => {
+            match triple.split('-').next() {
+                Some("x86_64") => Target::initialize_x86(&config),
+                Some("aarch64") => Target::initialize_aarch64(&config),
+                Some("riscv64") => Target::initialize_riscv(&config),
+                _ => {
+                    return Err(CompileError::Codegen(format!(
+                        "Unsupported target {}; expected an x86_64, aarch64 or riscv64 triple",
+                        triple
+                    )));
+                }
+            }
+            TargetMachine::normalize_triple(&TargetTriple::create(triple))
+        }
+    };
+    let triple_name = triple.as_str().to_string_lossy().into_owned();
+    let arch = triple_name.split('-').next().unwrap_or_default();
+    let (default_cpu, default_features) = arch_defaults(arch);
+    let (cpu, features) = match cpu {
+        Some("native") => (
+            TargetMachine::get_host_cpu_name().to_string(),
+            features.map_or_else(
+                || TargetMachine::get_host_cpu_features().to_string(),
+                str::to_string,
+            ),
+        ),
+        cpu => (
+            cpu.unwrap_or(default_cpu).to_string(),
+            features.unwrap_or(default_features).to_string(),
+        ),
+    };
+
+    let target = Target::from_triple(&triple)
+        .map_err(|e| CompileError::Codegen(format!("Unknown target: {}", e)))?;
+    let machine = target
+        .create_target_machine(
+            &triple,
+            &cpu,
+            &features,
+            level.codegen_level(),
+            RelocMode::PIC,
+            CodeModel::Default,
+        )
+        .ok_or_else(|| {
+            CompileError::Codegen(format!("Cannot create a target machine for {}", triple))
+        })?;
+    module.set_triple(&triple);
+    module.set_data_layout(&machine.get_target_data().get_data_layout());
+    if arch == "riscv64" {
+        let abi = module.get_context().metadata_string(riscv_abi(&features));
+        module.add_metadata_flag("target-abi", FlagBehavior::Error, abi);
+    }
+    Ok(machine)
+}
+
+/// The CPU and features clang targets by default on Linux for `arch`.
+fn arch_defaults(arch: &str) -> (&'static str, &'static str) {
+    match arch {
+        "x86_64" => ("x86-64", ""),
+        "aarch64" => ("generic", "+neon,+fp-armv8"),
+        // RV64GC, which Linux distributions require
+        "riscv64" => ("generic-rv64", "+m,+a,+f,+d,+c"),
+        _ => ("generic", ""),
+    }
+}
+
+/// The RISC-V calling convention for `features`, which passes floating point values
+/// in registers only as wide as the hardware has: `lp64d` for RV64GC, whose C
+/// libraries expect it.
+fn riscv_abi(features: &str) -> &'static str {
+    let has = |feature| features.split(',').any(|f| f == feature);
+    if has("+d") {
+        "lp64d"
+    } else if has("+f") {
+        "lp64f"
+    } else {
+        "lp64"
+    }
+}
+
+/// Run `passes`, a pipeline in the syntax of `opt -passes=`, over the module.
+pub fn optimize(
+    module: &Module,
+    machine: &TargetMachine,
+    passes: &str,
+) -> Result<(), CompileError> {
+    module
+        .run_passes(passes, machine, PassBuilderOptions::create())
+        .map_err(|e| {
+            CompileError::Codegen(format!(
+                "Cannot run passes {}: {}",
+                passes,
+                e.to_string().trim_end()
+            ))
+        })
+}
+
+/// Write the module as an object file or, with `FileType::Assembly`, as assembly.
+pub fn write_file(
+    module: &Module,
+    machine: &TargetMachine,
+    file_type: FileType,
+    path: &Path,
+) -> Result<(), CompileError> {
+    machine.write_to_file(module, file_type, path).map_err(|e| {
+        CompileError::Io(format!(
+            "Failed to write {}: {}",
+            path.display(),
+            e.to_string().trim_end()
+        ))
+    })
+}
+
+/// Link `object` against the C library into the executable `output`, with the
+/// system's `cc`.
+pub fn link(object: &Path, output: &Path) -> Result<(), CompileError> {
+    let status = Command::new("cc")
+        .arg(object)
+        .arg("-o")
+        .arg(output)
+        .status()
+        .map_err(|e| CompileError::Io(format!("Cannot run cc: {}", e)))?;
+    if !status.success() {
+        return Err(CompileError::Io(format!(
+            "cc failed to link {} ({})",
+            output.display(),
+            status
+        )));
+    }
+    Ok(())
+}
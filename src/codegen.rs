@@ -4,9 +4,12 @@ use inkwell::{
     AddressSpace, IntPredicate,
     builder::Builder,
     context::Context,
-    module::Module,
-    types::IntType,
-    values::{FunctionValue, IntValue, PointerValue},
+    intrinsics::Intrinsic,
+    module::{Linkage, Module},
+    types::{BasicType, BasicTypeEnum, IntType, StructType},
+    values::{
+        BasicMetadataValueEnum, BasicValueEnum, FunctionValue, IntValue, PointerValue, StructValue,
+    },
 };
 use std::collections::HashMap;
 
@@ -14,15 +17,62 @@ pub struct CodeGen<'ctx> {
     context: &'ctx Context,
     pub module: Module<'ctx>,
     builder: Builder<'ctx>,
-    i32_type: IntType<'ctx>,
+    entry_builder: Builder<'ctx>, // for stack slots, which all go in the entry block
+    i32_type: IntType<'ctx>,      // C's `int`, for libc calls and `main`
+    int_type: IntType<'ctx>,      // our `int`, which lengths and indexes are too
     printf_fn: FunctionValue<'ctx>,
     variables: HashMap<String, PointerValue<'ctx>>,
-    array_sizes: HashMap<String, usize>,
-    function_types: HashMap<String, (Vec<bool>, bool)>, // (param_is_array, returns_array)
+    values: HashMap<String, BasicValueEnum<'ctx>>, // `let` bindings kept as SSA values
+    scopes: Vec<Vec<Shadowed<'ctx>>>, // per enclosing if/while body, what its declarations hid
+    var_types: HashMap<String, Type>,
+    function_types: HashMap<String, (Vec<Type>, Type)>, // (param types, return type)
+    file: String,                                       // the source file, for runtime errors
+    bounds_checks: bool,
+    overflow_checks: bool,
+    loops: usize, // how many `while` loops enclose the code being generated
+}
+
+// Field indices of the `{ ptr data, i64 len, i64 cap }` header behind every list.
+const LIST_DATA: u32 = 0;
+const LIST_LEN: u32 = 1;
+const LIST_CAP: u32 = 2;
+
+/// What a declaration in a nested block hid, restored when the block ends.
+struct Shadowed<'ctx> {
+    name: String,
+    slot: Option<PointerValue<'ctx>>,
+    value: Option<BasicValueEnum<'ctx>>,
+    ty: Option<Type>,
+}
+
+fn restore_entry<V>(map: &mut HashMap<String, V>, key: &str, prev: Option<V>) {
+    match prev {
+        Some(v) => {
+            map.insert(key.to_string(), v);
+        }
+        None => {
+            map.remove(key);
+        }
+    }
+}
+
+/// An array value taken apart: a pointer to its first element, its `i64` length and
+/// its element type. Arrays are passed, returned and stored as `{ ptr, i64 len }`.
+struct ArrayRef<'ctx> {
+    ptr: PointerValue<'ctx>,
+    len: IntValue<'ctx>,
+    elem: Type,
 }
 
 impl<'ctx> CodeGen<'ctx> {
-    pub fn new(ctx: &'ctx Context, module: Module<'ctx>) -> Self {
+    /// A code generator for the program in `file`, which only names it in error messages.
+    pub fn new(
+        ctx: &'ctx Context,
+        module: Module<'ctx>,
+        file: &str,
+        bounds_checks: bool,
+        overflow_checks: bool,
+    ) -> Self {
         let builder = ctx.create_builder();
         let i32_type = ctx.i32_type();
 
@@ -34,17 +84,39 @@ impl<'ctx> CodeGen<'ctx> {
             context: ctx,
             module,
             builder,
+            entry_builder: ctx.create_builder(),
             i32_type,
+            int_type: ctx.i64_type(),
             printf_fn,
             variables: HashMap::new(),
-            array_sizes: HashMap::new(),
+            values: HashMap::new(),
+            scopes: Vec::new(),
+            var_types: HashMap::new(),
             function_types: HashMap::new(),
+            file: file.to_string(),
+            bounds_checks,
+            overflow_checks,
+            loops: 0,
         }
     }
 
     pub fn compile_program(&mut self, prog: &Program) -> Result<(), CompileError> {
-        self.analyze_function_types(prog)?;
+        // The type checker has annotated every signature, and replaced generic
+        // functions by their instances
+        for func in &prog.functions {
+            let params = func
+                .params
+                .iter()
+                .map(|p| p.ty.clone().unwrap_or(Type::INT))
+                .collect();
+            let ret = func.return_type.clone().unwrap_or(Type::INT);
+            self.function_types.insert(func.name.clone(), (params, ret));
+        }
 
+        // Declare every prototype first so calls don't depend on declaration order
+        for func in &prog.functions {
+            self.declare_function(&func.name);
+        }
         for func in &prog.functions {
             self.compile_function_decl(func)?;
         }
@@ -54,382 +126,277 @@ impl<'ctx> CodeGen<'ctx> {
         let entry = self.context.append_basic_block(main_fn, "entry");
         self.builder.position_at_end(entry);
 
-        for stmt in &prog.statements {
-            self.compile_statement(stmt, Some(main_fn))?;
-        }
-
-        self.builder
-            .build_return(Some(&self.i32_type.const_int(0, false)))?;
-        Ok(())
-    }
-
-    fn analyze_function_types(&mut self, prog: &Program) -> Result<(), CompileError> {
-        for func in &prog.functions {
-            let mut param_is_array = vec![false; func.params.len()];
-            let mut returns_array = false;
-
-            // Analyze function body for return type
-            for stmt in &func.body {
-                if let Statement::Return { expr } = stmt {
-                    if matches!(expr, Expr::ArrayLiteral(_)) {
-                        returns_array = true;
-                    }
-                }
-            }
+        self.compile_block(&prog.statements, Some(main_fn))?;
 
-            // Analyze calls to this function for parameter types
-            for other_func in &prog.functions {
-                for stmt in &other_func.body {
-                    self.analyze_stmt_for_calls(&func.name, &mut param_is_array, stmt, prog)?;
-                }
-            }
-            for stmt in &prog.statements {
-                self.analyze_stmt_for_calls(&func.name, &mut param_is_array, stmt, prog)?;
-            }
-
-            self.function_types
-                .insert(func.name.clone(), (param_is_array, returns_array));
+        if !self.block_terminated() {
+            self.builder
+                .build_return(Some(&self.i32_type.const_int(0, false)))?;
         }
         Ok(())
     }
 
-    fn analyze_stmt_for_calls(
-        &self,
-        func_name: &str,
-        param_is_array: &mut [bool],
-        stmt: &Statement,
-        prog: &Program,
-    ) -> Result<(), CompileError> {
-        match stmt {
-            Statement::ExprStmt(expr) | Statement::Return { expr } | Statement::Print { expr } => {
-                self.analyze_expr_for_calls(func_name, param_is_array, expr, prog)?;
-            }
-            Statement::VarDecl { expr, .. }
-            | Statement::LetDecl { expr, .. }
-            | Statement::Assign { expr, .. } => {
-                self.analyze_expr_for_calls(func_name, param_is_array, expr, prog)?;
-            }
-            Statement::IndexedAssign {
-                array, index, expr, ..
-            } => {
-                self.analyze_expr_for_calls(func_name, param_is_array, array, prog)?;
-                self.analyze_expr_for_calls(func_name, param_is_array, index, prog)?;
-                self.analyze_expr_for_calls(func_name, param_is_array, expr, prog)?;
-            }
-            Statement::If {
-                cond,
-                then_branch,
-                else_branch,
-            } => {
-                self.analyze_expr_for_calls(func_name, param_is_array, cond, prog)?;
-                for s in then_branch {
-                    self.analyze_stmt_for_calls(func_name, param_is_array, s, prog)?;
-                }
-                if let Some(else_branch) = else_branch {
-                    for s in else_branch {
-                        self.analyze_stmt_for_calls(func_name, param_is_array, s, prog)?;
-                    }
-                }
-            }
-            Statement::While { cond, body } => {
-                self.analyze_expr_for_calls(func_name, param_is_array, cond, prog)?;
-                for s in body {
-                    self.analyze_stmt_for_calls(func_name, param_is_array, s, prog)?;
-                }
-            }
-        }
-        Ok(())
+    /// Run LLVM's verifier over the finished module. Anything it rejects is a bug in
+    /// the code generator, reported against the toy function it was generating.
+    pub fn verify(&self) -> Result<(), CompileError> {
+        let Err(msg) = self.module.verify() else {
+            return Ok(());
+        };
+        let culprits: Vec<String> = self
+            .module
+            .get_functions()
+            .filter(|f| f.count_basic_blocks() > 0 && !f.verify(false))
+            .map(|f| match f.get_name().to_string_lossy().as_ref() {
+                "main" => "the top-level statements".to_string(),
+                "toy_list_grow" => "the list runtime".to_string(),
+                name => format!("function {}", name.strip_prefix("toy.").unwrap_or(name)),
+            })
+            .collect();
+        let place = if culprits.is_empty() {
+            "the module".to_string()
+        } else {
+            culprits.join(", ")
+        };
+        Err(CompileError::Codegen(format!(
+            "LLVM rejected the code generated for {}: {}",
+            place,
+            msg.to_string().trim_end()
+        )))
     }
 
-    fn analyze_expr_for_calls(
-        &self,
-        func_name: &str,
-        param_is_array: &mut [bool],
-        expr: &Expr,
-        prog: &Program,
-    ) -> Result<(), CompileError> {
-        match expr {
-            Expr::Call { name, args } if name == func_name => {
-                for (i, arg) in args.iter().enumerate() {
-                    if i >= param_is_array.len() {
-                        return Err(CompileError::Codegen(format!(
-                            "Too many arguments for function {}",
-                            func_name
-                        )));
-                    }
-                    if matches!(arg, Expr::Variable(var) if self.array_sizes.contains_key(var))
-                        || matches!(arg, Expr::ArrayLiteral(_))
-                    {
-                        param_is_array[i] = true;
-                    }
-                }
-            }
-            Expr::Length { array } => {
-                if let Expr::Variable(var) = &**array {
-                    // Check if the variable is a parameter of the function
-                    if let Some(func) = prog.functions.iter().find(|f| f.name == func_name) {
-                        if let Some(idx) = func.params.iter().position(|p| p == var) {
-                            param_is_array[idx] = true;
-                        }
-                    }
-                    self.analyze_expr_for_calls(func_name, param_is_array, array, prog)?;
-                }
-            }
-            Expr::Unary { expr, .. } => {
-                self.analyze_expr_for_calls(func_name, param_is_array, expr, prog)?;
-            }
-            Expr::Binary { left, right, .. } => {
-                self.analyze_expr_for_calls(func_name, param_is_array, left, prog)?;
-                self.analyze_expr_for_calls(func_name, param_is_array, right, prog)?;
-            }
-            Expr::Index { array, index } => {
-                self.analyze_expr_for_calls(func_name, param_is_array, array, prog)?;
-                self.analyze_expr_for_calls(func_name, param_is_array, index, prog)?;
-            }
-            _ => {}
-        }
-        Ok(())
+    /// Add user function `name` to the module, with the signature recorded for it.
+    fn declare_function(&mut self, name: &str) {
+        let (param_tys, return_type) = &self.function_types[name];
+        let param_types: Vec<_> = param_tys
+            .iter()
+            .map(|ty| self.llvm_type(ty).into())
+            .collect();
+        let fn_type = self.llvm_type(return_type).fn_type(&param_types, false);
+        self.module
+            .add_function(&symbol(name), fn_type, Some(Linkage::Internal));
     }
 
+    /// Compile the body of `f`, which must already be declared.
     fn compile_function_decl(&mut self, f: &Function) -> Result<(), CompileError> {
-        let (param_is_array, returns_array) = self.function_types.get(&f.name).unwrap().clone();
-        let mut param_types = Vec::new();
-        for is_array in &param_is_array {
-            if *is_array {
-                param_types.push(self.context.ptr_type(AddressSpace::default()).into()); // Array pointer
-                param_types.push(self.i32_type.into()); // Array size
-            } else {
-                param_types.push(self.i32_type.into()); // Scalar
-            }
-        }
-
-        let fn_type = if returns_array {
-            self.context
-                .ptr_type(AddressSpace::default())
-                .fn_type(&param_types, false)
-        } else {
-            self.i32_type.fn_type(&param_types, false)
-        };
-        let function = self.module.add_function(&f.name, fn_type, None);
+        let (param_tys, return_type) = self.function_types[&f.name].clone();
+        let function = self
+            .module
+            .get_function(&symbol(&f.name))
+            .ok_or_else(|| CompileError::Codegen(format!("{} was never declared", f.name)))?;
 
         let entry = self.context.append_basic_block(function, "entry");
         self.builder.position_at_end(entry);
 
         self.variables.clear();
-        self.array_sizes.clear();
-        let mut param_idx = 0;
-        for (i, pname) in f.params.iter().enumerate() {
-            if param_is_array[i] {
-                let ptr = function.get_nth_param(param_idx).ok_or_else(|| {
-                    CompileError::Codegen(format!("missing array pointer param for {}", pname))
-                })?;
-                let size = function.get_nth_param(param_idx + 1).ok_or_else(|| {
-                    CompileError::Codegen(format!("missing array size param for {}", pname))
-                })?;
-                let alloca = self
-                    .builder
-                    .build_alloca(self.context.ptr_type(AddressSpace::default()), pname)?;
-                self.builder.build_store(alloca, ptr)?;
-                self.variables.insert(pname.clone(), alloca);
-                // Store size as a variable
-                let size_alloca = self
-                    .builder
-                    .build_alloca(self.i32_type, &format!("{}_size", pname))?;
-                self.builder.build_store(size_alloca, size)?;
-                self.variables
-                    .insert(format!("{}_size", pname), size_alloca);
-                self.array_sizes.insert(pname.clone(), 0); // Placeholder, updated later
-                param_idx += 2;
-            } else {
-                let ptr = self.builder.build_alloca(self.i32_type, pname)?;
-                self.builder
-                    .build_store(ptr, function.get_nth_param(param_idx).unwrap())?;
-                self.variables.insert(pname.clone(), ptr);
-                param_idx += 1;
-            }
+        self.values.clear();
+        self.scopes.clear();
+        self.var_types.clear();
+        // Destructured parameters arrive in a hidden variable and are bound after the others
+        let mut destructured = Vec::new();
+        for (i, param) in f.params.iter().enumerate() {
+            let pname = &match param.pattern.name() {
+                Some(name) => name.to_string(),
+                None => {
+                    let hidden = format!("arg.{}", i);
+                    destructured.push(Statement::LetPattern {
+                        pattern: param.pattern.clone(),
+                        expr: Expr {
+                            kind: ExprKind::Variable(hidden.clone()),
+                            ty: Some(param_tys[i].clone()),
+                        },
+                        line: 0,
+                    });
+                    hidden
+                }
+            };
+            self.var_types.insert(pname.clone(), param_tys[i].clone());
+            let ptr = self.build_entry_alloca(self.llvm_type(&param_tys[i]), pname)?;
+            self.builder
+                .build_store(ptr, function.get_nth_param(i as u32).unwrap())?;
+            self.variables.insert(pname.clone(), ptr);
         }
 
-        for stmt in &f.body {
-            self.compile_statement(stmt, Some(function))?;
-        }
+        self.compile_block(destructured.iter().chain(&f.body), Some(function))?;
 
-        if returns_array {
-            let empty_array = self.i32_type.array_type(0);
-            let alloca = self.builder.build_alloca(empty_array, "empty_array")?;
-            self.builder.build_return(Some(&alloca))?;
-        } else {
+        // Only functions that return nothing, or loop forever, can get here
+        if !self.block_terminated() {
             self.builder
-                .build_return(Some(&self.i32_type.const_int(0, false)))?;
+                .build_return(Some(&self.llvm_type(&return_type).const_zero()))?;
+        }
+        Ok(())
+    }
+
+    /// Compile `stmts` in order, skipping any that follow a `return`.
+    fn compile_block<'s>(
+        &mut self,
+        stmts: impl IntoIterator<Item = &'s Statement>,
+        current_fn: Option<FunctionValue<'ctx>>,
+    ) -> Result<(), CompileError> {
+        for stmt in stmts {
+            if self.block_terminated() {
+                break;
+            }
+            self.compile_statement(stmt, current_fn)?;
         }
         Ok(())
     }
 
+    /// Whether the block being filled already ends in a branch or return.
+    fn block_terminated(&self) -> bool {
+        self.builder
+            .get_insert_block()
+            .and_then(|bb| bb.get_terminator())
+            .is_some()
+    }
+
     fn compile_statement(
         &mut self,
         stmt: &Statement,
         current_fn: Option<FunctionValue<'ctx>>,
     ) -> Result<(), CompileError> {
         match stmt {
-            Statement::VarDecl { name, expr } => {
-                let ptr = if let Expr::ArrayLiteral(elems) = expr {
-                    self.compile_array_literal(elems, name)?
-                } else {
-                    let val = self.compile_expr(expr)?;
-                    let ptr = self.builder.build_alloca(self.i32_type, name)?;
-                    self.builder.build_store(ptr, val)?;
-                    ptr
-                };
+            Statement::VarDecl {
+                name,
+                ty,
+                expr: None,
+                ..
+            } => {
+                // Flow analysis guarantees a store before any load
+                let ty = ty.clone().ok_or_else(|| {
+                    CompileError::Codegen(format!(
+                        "The type of {} is unknown here; give it a type annotation",
+                        name
+                    ))
+                })?;
+                self.shadow(name);
+                self.values.remove(name);
+                let ptr = self.build_entry_alloca(self.llvm_type(&ty), name)?;
                 self.variables.insert(name.clone(), ptr);
+                self.var_types.insert(name.clone(), ty);
             }
-            Statement::LetDecl { name, expr } => {
-                let ptr = if let Expr::ArrayLiteral(elems) = expr {
-                    let array_ptr = self.compile_array_literal(elems, name)?;
-                    let ptr = self
-                        .builder
-                        .build_alloca(self.context.ptr_type(AddressSpace::default()), name)?;
-                    self.builder.build_store(ptr, array_ptr)?;
-                    // Store the actual array size
-                    let size = elems.len();
-                    self.array_sizes.insert(name.clone(), size); // Fix: Use actual size instead of 0
-                    let size_alloca = self
-                        .builder
-                        .build_alloca(self.i32_type, &format!("{}_size", name))?;
-                    self.builder
-                        .build_store(size_alloca, self.i32_type.const_int(size as u64, false))?;
-                    self.variables.insert(format!("{}_size", name), size_alloca);
-                    ptr
-                } else if let Expr::Variable(var_name) = expr {
-                    if self.array_sizes.contains_key(var_name) {
-                        let array_ptr = self.load_array_ptr(var_name)?;
-                        let ptr = self
-                            .builder
-                            .build_alloca(self.context.ptr_type(AddressSpace::default()), name)?;
-                        self.builder.build_store(ptr, array_ptr)?;
-                        let size_ptr = self
-                            .variables
-                            .get(&format!("{}_size", var_name))
-                            .ok_or_else(|| {
-                                CompileError::Codegen(format!(
-                                    "undefined array size for {}",
-                                    var_name
-                                ))
-                            })?;
-                        let size =
-                            self.builder
-                                .build_load(self.i32_type, *size_ptr, "load_size")?;
-                        let size_alloca = self
-                            .builder
-                            .build_alloca(self.i32_type, &format!("{}_size", name))?;
-                        self.builder.build_store(size_alloca, size)?;
-                        self.variables.insert(format!("{}_size", name), size_alloca);
-                        self.array_sizes.insert(name.clone(), 0); // This is fine for variables, as size is stored separately
-                        ptr
-                    } else {
-                        let val = self.compile_expr(expr)?;
-                        let ptr = self.builder.build_alloca(self.i32_type, name)?;
-                        self.builder.build_store(ptr, val)?;
-                        ptr
-                    }
+            Statement::VarDecl {
+                name,
+                ty,
+                expr: Some(expr),
+                ..
+            }
+            | Statement::LetDecl { name, ty, expr, .. } => {
+                let ty = ty.clone().unwrap_or_else(|| self.expr_type(expr));
+                // `let x = x + 1` reads the binding it shadows
+                let val = self.compile_value(expr)?;
+                self.shadow(name);
+                self.values.remove(name);
+                // A `let` dominates every use in its block and is never assigned to,
+                // so it needs no stack slot
+                if matches!(stmt, Statement::LetDecl { .. }) {
+                    val.set_name(name);
+                    self.values.insert(name.clone(), val);
+                    self.variables.remove(name);
                 } else {
-                    let val = self.compile_expr(expr)?;
-                    let ptr = self.builder.build_alloca(self.i32_type, name)?;
+                    let ptr = self.build_entry_alloca(self.llvm_type(&ty), name)?;
                     self.builder.build_store(ptr, val)?;
-                    ptr
-                };
-                self.variables.insert(name.clone(), ptr);
+                    self.variables.insert(name.clone(), ptr);
+                }
+                self.var_types.insert(name.clone(), ty);
+            }
+            Statement::LetPattern { pattern, expr, .. } => {
+                // Catch length mismatches at compile time when the size is known statically
+                if let Pattern::Array(elems) = pattern
+                    && let Some(size) = expr.array_len()
+                    && size != elems.len()
+                {
+                    return Err(CompileError::Codegen(format!(
+                        "Array pattern expects {} elements, but the array has {}",
+                        elems.len(),
+                        size
+                    )));
+                }
+                let ty = self.expr_type(expr);
+                let val = self.compile_value(expr)?;
+                self.bind_pattern(pattern, val, &ty)?;
             }
-            Statement::Assign { name, expr } => {
+            Statement::Assign { name, expr, .. } => {
                 let ptr = *self
                     .variables
                     .get(name)
                     .ok_or_else(|| CompileError::Codegen(format!("undefined variable {}", name)))?;
-                if let Expr::ArrayLiteral(elems) = expr {
-                    let new_ptr = self.compile_array_literal(elems, name)?;
-                    self.variables.insert(name.clone(), new_ptr);
-                    self.array_sizes.insert(name.clone(), elems.len());
-                } else if let Expr::Call { name: fn_name, .. } = expr {
-                    let returns_array = self
-                        .function_types
-                        .get(fn_name)
-                        .map(|(_, ret)| *ret)
-                        .unwrap_or(false);
-                    if returns_array {
-                        let val = self.compile_expr(expr)?;
-                        let ptr_val = self.builder.build_int_to_ptr(
-                            val,
-                            self.context.ptr_type(AddressSpace::default()),
-                            "int_to_ptr",
-                        )?;
-                        let alloca = self
-                            .builder
-                            .build_alloca(self.context.ptr_type(AddressSpace::default()), name)?;
-                        self.builder.build_store(alloca, ptr_val)?;
-                        self.variables.insert(name.clone(), alloca);
-                        self.array_sizes
-                            .insert(name.clone(), self.get_array_size_from_context(name)?);
-                    } else {
-                        let val = self.compile_expr(expr)?;
-                        self.builder.build_store(ptr, val)?;
-                    }
-                } else {
-                    let val = self.compile_expr(expr)?;
+                let val = self.compile_value(expr)?;
+                self.builder.build_store(ptr, val)?;
+            }
+            Statement::IndexedAssign {
+                array,
+                index,
+                expr,
+                line,
+            } => {
+                if let Type::List(elem) = self.expr_type(array) {
+                    let list = self.compile_list(array)?;
+                    let idx = self.compile_index(index)?;
+                    let val = self.compile_value(expr)?;
+                    let ptr = self.list_element_ptr(list, &elem, idx, *line)?;
                     self.builder.build_store(ptr, val)?;
+                    return Ok(());
                 }
-            }
-            Statement::IndexedAssign { array, index, expr } => {
-                let array_name = match &**array {
-                    Expr::Variable(name) => name,
-                    _ => {
-                        return Err(CompileError::Codegen(
-                            "Array in indexed assignment must be a variable".into(),
-                        ));
-                    }
-                };
-                let array_ptr = self.load_array_ptr(array_name)?;
-                let idx = self.compile_expr(index)?;
-                let val = self.compile_expr(expr)?;
-                let array_type = self.i32_type.array_type(0);
-                let ptr = unsafe {
-                    self.builder.build_in_bounds_gep(
-                        array_type,
-                        array_ptr,
-                        &[self.i32_type.const_int(0, false), idx],
-                        "index_ptr",
-                    )?
-                };
+                let arr = self.compile_array(array)?;
+                let idx = self.compile_index(index)?;
+                let val = self.compile_value(expr)?;
+                let ptr = self.checked_element_ptr(&arr, idx, array, *line)?;
                 self.builder.build_store(ptr, val)?;
             }
-            Statement::Print { expr } => match expr {
-                Expr::StrLiteral(s) => {
+            Statement::Print { expr } => match self.expr_type(expr) {
+                Type::Str => {
                     let fmt = self.builder.build_global_string_ptr("%s\n\0", "fmt")?;
-                    let str_gv = self
-                        .builder
-                        .build_global_string_ptr(&format!("{}\0", s), "str")?;
+                    let str_ptr = self.compile_string(expr)?;
+                    self.builder.build_call(
+                        self.printf_fn,
+                        &[fmt.as_pointer_value().into(), str_ptr.into()],
+                        "print_call",
+                    )?;
+                }
+                Type::Bool => {
+                    let val = self.compile_expr(expr)?;
+                    let fmt = self.builder.build_global_string_ptr("%s\n\0", "fmt")?;
+                    let t = self.builder.build_global_string_ptr("true", "true_str")?;
+                    let f = self.builder.build_global_string_ptr("false", "false_str")?;
+                    let text = self.builder.build_select(
+                        val,
+                        t.as_pointer_value(),
+                        f.as_pointer_value(),
+                        "bool_str",
+                    )?;
                     self.builder.build_call(
                         self.printf_fn,
-                        &[
-                            fmt.as_pointer_value().into(),
-                            str_gv.as_pointer_value().into(),
-                        ],
+                        &[fmt.as_pointer_value().into(), text.into()],
                         "print_call",
                     )?;
                 }
-                _ => {
+                Type::Int(kind) => {
                     let val = self.compile_expr(expr)?;
-                    let fmt = self.builder.build_global_string_ptr("%d\n\0", "fmt")?;
+                    let (spec, val) = self.printf_int(val, kind)?;
+                    let fmt = self
+                        .builder
+                        .build_global_string_ptr(&format!("{}\n\0", spec), "fmt")?;
                     self.builder.build_call(
                         self.printf_fn,
                         &[fmt.as_pointer_value().into(), val.into()],
                         "print_call",
                     )?;
                 }
+                ty => {
+                    return Err(CompileError::Codegen(format!("Cannot print {}", ty)));
+                }
             },
-            Statement::Return { expr } => {
-                if let Expr::ArrayLiteral(elems) = expr {
-                    let array_ptr = self.compile_array_literal(elems, "ret_array")?;
-                    self.builder.build_return(Some(&array_ptr))?;
+            Statement::Return { expr, .. } => {
+                let val = self.compile_value(expr)?;
+                if self.in_main() {
+                    // Top-level code is C's `main`, which returns an `int`; only the low
+                    // bits of the value reach the exit status anyway
+                    let status = self.builder.build_int_truncate(
+                        val.into_int_value(),
+                        self.i32_type,
+                        "status",
+                    )?;
+                    self.builder.build_return(Some(&status))?;
                 } else {
-                    let val = self.compile_expr(expr)?;
                     self.builder.build_return(Some(&val))?;
                 }
             }
@@ -437,12 +404,9 @@ impl<'ctx> CodeGen<'ctx> {
                 cond,
                 then_branch,
                 else_branch,
+                ..
             } => {
-                let test = self.compile_expr(cond)?;
-                let zero = self.i32_type.const_int(0, false);
-                let cond_i1 =
-                    self.builder
-                        .build_int_compare(IntPredicate::NE, test, zero, "ifcond")?;
+                let cond_i1 = self.compile_expr(cond)?;
                 let parent = current_fn.unwrap();
                 let then_bb = self.context.append_basic_block(parent, "then");
                 let else_bb = self.context.append_basic_block(parent, "else");
@@ -452,38 +416,33 @@ impl<'ctx> CodeGen<'ctx> {
                     .build_conditional_branch(cond_i1, then_bb, else_bb)?;
 
                 self.builder.position_at_end(then_bb);
-                for s in then_branch {
-                    self.compile_statement(s, current_fn)?;
-                }
-                if self
-                    .builder
-                    .get_insert_block()
-                    .unwrap()
-                    .get_terminator()
-                    .is_none()
-                {
+                self.scopes.push(Vec::new());
+                self.compile_block(then_branch, current_fn)?;
+                self.pop_scope();
+                if !self.block_terminated() {
                     self.builder.build_unconditional_branch(merge_bb)?;
                 }
 
                 self.builder.position_at_end(else_bb);
                 if let Some(els) = else_branch {
-                    for s in els {
-                        self.compile_statement(s, current_fn)?;
-                    }
+                    self.scopes.push(Vec::new());
+                    self.compile_block(els, current_fn)?;
+                    self.pop_scope();
                 }
-                if self
-                    .builder
-                    .get_insert_block()
-                    .unwrap()
-                    .get_terminator()
-                    .is_none()
-                {
+                if !self.block_terminated() {
                     self.builder.build_unconditional_branch(merge_bb)?;
                 }
 
-                self.builder.position_at_end(merge_bb);
+                if merge_bb.get_first_use().is_some() {
+                    self.builder.position_at_end(merge_bb);
+                } else {
+                    // Both branches returned, so nothing after the `if` can run
+                    merge_bb.remove_from_function().map_err(|_| {
+                        CompileError::Codegen("failed to remove unreachable block".into())
+                    })?;
+                }
             }
-            Statement::While { cond, body } => {
+            Statement::While { cond, body, .. } => {
                 let parent = current_fn.unwrap();
                 let loop_bb = self.context.append_basic_block(parent, "loop");
                 let after_bb = self.context.append_basic_block(parent, "after");
@@ -491,27 +450,19 @@ impl<'ctx> CodeGen<'ctx> {
                 self.builder.build_unconditional_branch(loop_bb)?;
                 self.builder.position_at_end(loop_bb);
 
-                let test = self.compile_expr(cond)?;
-                let zero = self.i32_type.const_int(0, false);
-                let cond_i1 =
-                    self.builder
-                        .build_int_compare(IntPredicate::NE, test, zero, "whilecond")?;
+                self.loops += 1;
+                let cond_i1 = self.compile_expr(cond)?;
 
                 let body_bb = self.context.append_basic_block(parent, "body");
                 self.builder
                     .build_conditional_branch(cond_i1, body_bb, after_bb)?;
 
                 self.builder.position_at_end(body_bb);
-                for s in body {
-                    self.compile_statement(s, current_fn)?;
-                }
-                if self
-                    .builder
-                    .get_insert_block()
-                    .unwrap()
-                    .get_terminator()
-                    .is_none()
-                {
+                self.scopes.push(Vec::new());
+                self.compile_block(body, current_fn)?;
+                self.pop_scope();
+                self.loops -= 1;
+                if !self.block_terminated() {
                     self.builder.build_unconditional_branch(loop_bb)?;
                 }
 
@@ -524,15 +475,31 @@ impl<'ctx> CodeGen<'ctx> {
         Ok(())
     }
 
+    /// Compile array literal `expr`, whose elements are `elems`.
     fn compile_array_literal(
         &mut self,
+        expr: &Expr,
         elems: &[Expr],
         name: &str,
-    ) -> Result<PointerValue<'ctx>, CompileError> {
-        let array_type = self.i32_type.array_type(elems.len() as u32);
-        let alloca = self.builder.build_alloca(array_type, name)?;
-        for (i, elem) in elems.iter().enumerate() {
-            let val = self.compile_expr(elem)?;
+    ) -> Result<ArrayRef<'ctx>, CompileError> {
+        let Type::Array(elem) = self.expr_type(expr) else {
+            unreachable!("array literal checked to be an array")
+        };
+        let elem = *elem;
+        let array_type = self.llvm_type(&elem).array_type(elems.len() as u32);
+        // An array made inside a function may outlive its frame, by being returned or
+        // stored somewhere the caller can see; main's frame lasts as long as the program.
+        // In a loop, though, every iteration needs an array of its own.
+        let alloca = if self.in_main() && self.loops == 0 {
+            self.build_entry_alloca(array_type, name)?
+        } else {
+            let size = array_type
+                .size_of()
+                .ok_or_else(|| CompileError::Codegen("array has no size".into()))?;
+            self.build_malloc_bytes(size)?
+        };
+        for (i, e) in elems.iter().enumerate() {
+            let val = self.compile_value(e)?;
             let ptr = unsafe {
                 self.builder.build_in_bounds_gep(
                     array_type,
@@ -546,270 +513,1435 @@ impl<'ctx> CodeGen<'ctx> {
             };
             self.builder.build_store(ptr, val)?;
         }
-        self.array_sizes.insert(name.to_string(), elems.len());
-        Ok(alloca)
+        Ok(ArrayRef {
+            ptr: alloca,
+            len: self.context.i64_type().const_int(elems.len() as u64, false),
+            elem,
+        })
     }
 
-    fn load_array_ptr(&mut self, array_name: &str) -> Result<PointerValue<'ctx>, CompileError> {
-        let ptr = self
-            .variables
-            .get(array_name)
-            .ok_or_else(|| CompileError::Codegen(format!("undefined array {}", array_name)))?;
-        if self.array_sizes.contains_key(array_name) {
-            let loaded = self.builder.build_load(
-                self.context.ptr_type(AddressSpace::default()),
-                *ptr,
-                "load_array_ptr",
-            )?;
-            Ok(loaded.into_pointer_value())
-        } else {
-            Ok(*ptr)
+    /// A stack slot in the entry block of the current function. A slot made anywhere
+    /// else would be allocated again every time its block runs, growing the stack
+    /// on each iteration of a loop.
+    fn build_entry_alloca<T: BasicType<'ctx>>(
+        &self,
+        ty: T,
+        name: &str,
+    ) -> Result<PointerValue<'ctx>, CompileError> {
+        let entry = self
+            .builder
+            .get_insert_block()
+            .and_then(|bb| bb.get_parent())
+            .and_then(|f| f.get_first_basic_block())
+            .ok_or_else(|| CompileError::Codegen("stack slot outside a function".into()))?;
+        match entry.get_first_instruction() {
+            Some(first) => self.entry_builder.position_before(&first),
+            None => self.entry_builder.position_at_end(entry),
         }
+        Ok(self.entry_builder.build_alloca(ty, name)?)
+    }
+
+    /// Whether the code being generated is top-level code, which makes up `main`.
+    fn in_main(&self) -> bool {
+        self.builder
+            .get_insert_block()
+            .and_then(|bb| bb.get_parent())
+            .is_some_and(|f| f.get_name().to_bytes() == b"main")
     }
 
-    fn get_array_size_from_context(&self, name: &str) -> Result<usize, CompileError> {
-        self.array_sizes
+    /// Read a non-array variable, either a `let` kept as an SSA value or a stack slot.
+    fn load_variable(&mut self, name: &str) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        if let Some(val) = self.values.get(name) {
+            return Ok(*val);
+        }
+        let ty = self.llvm_type(&self.var_types.get(name).cloned().unwrap_or(Type::INT));
+        let ptr = *self
+            .variables
             .get(name)
-            .copied()
-            .ok_or_else(|| CompileError::Codegen(format!("Array size not found for {}", name)))
+            .ok_or_else(|| CompileError::Codegen(format!("undefined var {}", name)))?;
+        Ok(self.builder.build_load(ty, ptr, name)?)
     }
 
-    fn compile_expr(&mut self, expr: &Expr) -> Result<IntValue<'ctx>, CompileError> {
-        match expr {
-            Expr::Number(n) => Ok(self.i32_type.const_int(*n as u64, true)),
-            Expr::Bool(b) => {
-                let i1 = self
-                    .context
-                    .bool_type()
-                    .const_int(if *b { 1 } else { 0 }, false);
-                Ok(self
-                    .builder
-                    .build_int_z_extend(i1, self.i32_type, "bool2int")?)
-            }
-            Expr::StrLiteral(s) => {
-                let gs = self
-                    .builder
-                    .build_global_string_ptr(&format!("{}\0", s), "strlit")?;
-                let ptr_val = gs.as_pointer_value();
-                let cast = self.builder.build_bit_cast(
-                    ptr_val,
-                    self.context.ptr_type(AddressSpace::default()),
-                    "strtoint",
-                )?;
-                Ok(cast.into_int_value())
-            }
-            Expr::Variable(name) => {
-                if self.array_sizes.contains_key(name) {
-                    let ptr = self.load_array_ptr(name)?;
-                    let cast = self
-                        .builder
-                        .build_ptr_to_int(ptr, self.i32_type, "array_to_i32")?;
-                    Ok(cast)
-                } else {
-                    let ptr = self
-                        .variables
-                        .get(name)
-                        .ok_or_else(|| CompileError::Codegen(format!("undefined var {}", name)))?;
-                    let loaded = self.builder.build_load(self.i32_type, *ptr, name)?;
-                    Ok(loaded.into_int_value())
-                }
-            }
-            Expr::Unary { op, expr } => {
-                let v = self.compile_expr(expr)?;
-                match op {
-                    UnOp::Pos => Ok(v),
-                    UnOp::Neg => Ok(self.builder.build_int_sub(
-                        self.i32_type.const_int(0, false),
-                        v,
-                        "negtmp",
-                    )?),
-                }
+    /// Before declaring `name` in the innermost block, remember what it referred to
+    /// so that `pop_scope` can put it back.
+    fn shadow(&mut self, name: &str) {
+        let Some(scope) = self.scopes.last() else {
+            return; // function level: nothing outlives it
+        };
+        if scope.iter().any(|s| s.name == name) {
+            return;
+        }
+        let saved = Shadowed {
+            name: name.to_string(),
+            slot: self.variables.get(name).copied(),
+            value: self.values.get(name).copied(),
+            ty: self.var_types.get(name).cloned(),
+        };
+        self.scopes.last_mut().unwrap().push(saved);
+    }
+
+    /// Leave a block: its declarations go out of scope and the ones they shadowed return.
+    fn pop_scope(&mut self) {
+        for saved in self.scopes.pop().unwrap_or_default() {
+            restore_entry(&mut self.variables, &saved.name, saved.slot);
+            restore_entry(&mut self.values, &saved.name, saved.value);
+            restore_entry(&mut self.var_types, &saved.name, saved.ty);
+        }
+    }
+
+    /// Bind the names in `pattern` to the matching parts of `val`, a value of type `ty`.
+    fn bind_pattern(
+        &mut self,
+        pattern: &Pattern,
+        val: BasicValueEnum<'ctx>,
+        ty: &Type,
+    ) -> Result<(), CompileError> {
+        match pattern {
+            Pattern::Wildcard => Ok(()),
+            Pattern::Ident(name) => {
+                self.shadow(name);
+                let ptr = self.build_entry_alloca(self.llvm_type(ty), name)?;
+                self.builder.build_store(ptr, val)?;
+                self.var_types.insert(name.clone(), ty.clone());
+                self.values.remove(name);
+                self.variables.insert(name.clone(), ptr);
+                Ok(())
             }
-            Expr::Binary { op, left, right } => {
-                let l = self.compile_expr(left)?;
-                let r = self.compile_expr(right)?;
-                let rv = match op {
-                    BinOp::Add => self.builder.build_int_add(l, r, "addtmp")?,
-                    BinOp::Sub => self.builder.build_int_sub(l, r, "subtmp")?,
-                    BinOp::Mul => self.builder.build_int_mul(l, r, "multmp")?,
-                    BinOp::Div => self.builder.build_int_signed_div(l, r, "divtmp")?,
-                    BinOp::Rem => self.builder.build_int_signed_rem(l, r, "remtmp")?,
-                    BinOp::Lt => self.build_int_cmp(IntPredicate::SLT, l, r, "lttmp")?,
-                    BinOp::Le => self.build_int_cmp(IntPredicate::SLE, l, r, "letmp")?,
-                    BinOp::Gt => self.build_int_cmp(IntPredicate::SGT, l, r, "gttmp")?,
-                    BinOp::Ge => self.build_int_cmp(IntPredicate::SGE, l, r, "getmp")?,
-                    BinOp::Eq => self.build_int_cmp(IntPredicate::EQ, l, r, "eqtmp")?,
-                    BinOp::Ne => self.build_int_cmp(IntPredicate::NE, l, r, "netmp")?,
+            Pattern::Tuple(elems) => {
+                let Type::Tuple(elem_types) = ty else {
+                    return Err(CompileError::Codegen(format!(
+                        "Cannot destructure {} as a tuple",
+                        ty
+                    )));
                 };
-                Ok(rv)
-            }
-            Expr::Call { name, args } => {
-                if name == "length" && args.len() == 1 {
-                    if let Some(size) = args[0].array_len() {
-                        // For literals: use constant directly
-                        return Ok(self.i32_type.const_int(size as u64, false));
-                    } else if let Expr::Variable(var_name) = &args[0] {
-                        if self.array_sizes.contains_key(var_name) {
-                            let ptr = self.load_array_ptr(var_name)?;
-                            let neg_one = self.i32_type.const_int((-1i64) as u64, true);
-                            let len_ptr = unsafe {
-                                self.builder.build_in_bounds_gep(
-                                    self.i32_type,
-                                    ptr,
-                                    &[neg_one],
-                                    "len_ptr",
-                                )?
-                            };
-                            let len = self.builder.build_load(self.i32_type, len_ptr, "len_val")?;
-                            return Ok(len.into_int_value());
-                        }
-                    }
-
+                if elem_types.len() != elems.len() {
                     return Err(CompileError::Codegen(format!(
-                        "undefined array size for {}",
-                        match &args[0] {
-                            Expr::Variable(v) => v.clone(),
-                            _ => "expression".to_string(),
-                        }
+                        "Cannot destructure a tuple of {} elements into {} patterns",
+                        elem_types.len(),
+                        elems.len()
                     )));
                 }
-
-                let fn_val = self
-                    .module
-                    .get_function(name)
-                    .ok_or_else(|| CompileError::Codegen(format!("unknown fn {}", name)))?;
-                let (param_is_array, returns_array) =
-                    self.function_types.get(name).unwrap().clone();
-                let mut compiled_args = Vec::new();
-                for (i, arg) in args.iter().enumerate() {
-                    if i < param_is_array.len() && param_is_array[i] {
-                        if let Expr::Variable(var_name) = arg {
-                            if self.array_sizes.contains_key(var_name) {
-                                let ptr = self.load_array_ptr(var_name)?;
-                                let size = *self.array_sizes.get(var_name).unwrap();
-                                compiled_args.push(ptr.into());
-                                compiled_args
-                                    .push(self.i32_type.const_int(size as u64, false).into());
-                                continue;
-                            }
-                        } else if let Expr::ArrayLiteral(elems) = arg {
-                            let ptr = self.compile_array_literal(elems, "arg_array")?;
-                            compiled_args.push(ptr.into());
-                            compiled_args
-                                .push(self.i32_type.const_int(elems.len() as u64, false).into());
-                            continue;
-                        }
-                        return Err(CompileError::Codegen(format!(
-                            "Expected array argument for parameter {} of {}",
-                            i, name
-                        )));
-                    }
-                    let val = self.compile_expr(arg)?;
-                    compiled_args.push(val.into());
-                }
-                let call_site = self.builder.build_call(fn_val, &compiled_args, "calltmp")?;
-                if returns_array {
-                    let ptr = call_site
-                        .try_as_basic_value()
-                        .left()
-                        .ok_or_else(|| CompileError::Codegen("array return expected".into()))?
-                        .into_pointer_value();
-                    let cast = self
-                        .builder
-                        .build_ptr_to_int(ptr, self.i32_type, "array_to_i32")?;
-                    Ok(cast)
-                } else {
-                    Ok(call_site
-                        .try_as_basic_value()
-                        .left()
-                        .ok_or_else(|| CompileError::Codegen("scalar return expected".into()))?
-                        .into_int_value())
+                let tuple = val.into_struct_value();
+                for (i, (elem, elem_ty)) in elems.iter().zip(elem_types).enumerate() {
+                    let field = self.builder.build_extract_value(tuple, i as u32, "field")?;
+                    self.bind_pattern(elem, field, elem_ty)?;
                 }
+                Ok(())
             }
-            Expr::ArrayLiteral(elems) => {
-                let array_ptr = self.compile_array_literal(elems, "array")?;
-                let cast =
-                    self.builder
-                        .build_ptr_to_int(array_ptr, self.i32_type, "array_to_i32")?;
-                Ok(cast)
-            }
-            Expr::Index { array, index } => {
-                let array_name = match &**array {
-                    Expr::Variable(name) => name,
-                    _ => {
-                        return Err(CompileError::Codegen(
-                            "Array indexing must use a variable".into(),
-                        ));
-                    }
-                };
-                let array_ptr = self.load_array_ptr(array_name)?;
-                let idx = self.compile_expr(index)?;
-                let size = *self.array_sizes.get(array_name).ok_or_else(|| {
-                    CompileError::Codegen(format!("undefined array {}", array_name))
-                })?;
-                let idx_val = idx.get_sign_extended_constant().ok_or_else(|| {
-                    CompileError::Codegen("Index must be a constant or resolvable integer".into())
-                })?;
-                if idx_val < 0 || idx_val as usize >= size {
+            Pattern::Array(elems) => {
+                let Type::Array(elem_ty) = ty else {
                     return Err(CompileError::Codegen(format!(
-                        "Index {} out of bounds for array {} of size {}",
-                        idx_val, array_name, size
+                        "Cannot destructure {} as an array",
+                        ty
                     )));
-                }
-                let array_type = self.i32_type.array_type(0);
-                let ptr = unsafe {
-                    self.builder.build_in_bounds_gep(
-                        array_type,
-                        array_ptr,
-                        &[self.i32_type.const_int(0, false), idx],
-                        "index_ptr",
-                    )?
                 };
-                let loaded = self.builder.build_load(self.i32_type, ptr, "index_load")?;
-                Ok(loaded.into_int_value())
-            }
-            Expr::Length { array } => {
-                let array_name = match &**array {
-                    Expr::Variable(name) => name,
-                    _ => {
+                let arr = self.array_ref(val.into_struct_value(), elem_ty)?;
+                let expected = self.context.i64_type().const_int(elems.len() as u64, false);
+                let matches = self.builder.build_int_compare(
+                    IntPredicate::EQ,
+                    arr.len,
+                    expected,
+                    "len_ok",
+                )?;
+                self.build_runtime_check(
+                    matches,
+                    "array pattern expects %lld elements, found %lld",
+                    &[expected.into(), arr.len.into()],
+                )?;
+                let llvm_elem = self.llvm_type(elem_ty);
+                for (i, elem) in elems.iter().enumerate() {
+                    let idx = self.int_type.const_int(i as u64, false);
+                    let ptr = self.element_ptr(&arr, idx)?;
+                    let loaded = self.builder.build_load(llvm_elem, ptr, "elem")?;
+                    self.bind_pattern(elem, loaded, elem_ty)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Compile an expression that evaluates to an array.
+    fn compile_array(&mut self, expr: &Expr) -> Result<ArrayRef<'ctx>, CompileError> {
+        match &expr.kind {
+            ExprKind::ArrayLiteral(elems) => self.compile_array_literal(expr, elems, "array"),
+            ExprKind::Variable(name) => {
+                let Some(Type::Array(elem)) = self.var_types.get(name).cloned() else {
+                    return Err(CompileError::Codegen(format!("{} is not an array", name)));
+                };
+                let val = self.load_variable(name)?.into_struct_value();
+                self.array_ref(val, &elem)
+            }
+            ExprKind::Index { array, index, line } => {
+                let Type::Array(elem) = self.expr_type(expr) else {
+                    return Err(CompileError::Codegen(
+                        "Cannot index into an element that is not an array".into(),
+                    ));
+                };
+                let row = self
+                    .compile_element(array, index, *line)?
+                    .into_struct_value();
+                self.array_ref(row, &elem)
+            }
+            ExprKind::Call { name, args } => {
+                let Type::Array(elem) = self.expr_type(expr) else {
+                    return Err(CompileError::Codegen(format!(
+                        "{} does not return an array",
+                        name
+                    )));
+                };
+                let val = self.compile_call(name, args)?.into_struct_value();
+                self.array_ref(val, &elem)
+            }
+            ExprKind::MethodCall {
+                receiver,
+                method,
+                args,
+                line,
+            } => {
+                let Type::Array(elem) = self.expr_type(expr) else {
+                    return Err(CompileError::Codegen(format!(
+                        "{}() does not return an array",
+                        method
+                    )));
+                };
+                let val = self
+                    .compile_method_call(receiver, method, args, *line)?
+                    .into_struct_value();
+                self.array_ref(val, &elem)
+            }
+            _ => Err(CompileError::Codegen("Expected an array".into())),
+        }
+    }
+
+    /// Pointer to element `idx` of `arr`.
+    fn element_ptr(
+        &self,
+        arr: &ArrayRef<'ctx>,
+        idx: IntValue<'ctx>,
+    ) -> Result<PointerValue<'ctx>, CompileError> {
+        let ptr = unsafe {
+            self.builder.build_in_bounds_gep(
+                self.llvm_type(&arr.elem),
+                arr.ptr,
+                &[idx],
+                "index_ptr",
+            )?
+        };
+        Ok(ptr)
+    }
+
+    /// Pointer to element `idx` of `array`, whose value is `arr`. The index is checked
+    /// against the length at compile time when both are known, and otherwise at runtime
+    /// unless bounds checks are turned off.
+    fn checked_element_ptr(
+        &mut self,
+        arr: &ArrayRef<'ctx>,
+        idx: IntValue<'ctx>,
+        array: &Expr,
+        line: usize,
+    ) -> Result<PointerValue<'ctx>, CompileError> {
+        let what = match &array.kind {
+            ExprKind::Variable(name) => format!("array '{}'", name),
+            _ => "array".to_string(),
+        };
+        if let Some(idx) = idx.get_sign_extended_constant()
+            && let Some(size) = arr.len.get_zero_extended_constant()
+            && (idx < 0 || idx as u64 >= size)
+        {
+            return Err(CompileError::Codegen(format!(
+                "Index {} out of bounds for {} of length {} at {}:{}",
+                idx, what, size, self.file, line
+            )));
+        }
+        if self.bounds_checks {
+            // Negative indexes wrap around to huge unsigned ones
+            let in_bounds =
+                self.builder
+                    .build_int_compare(IntPredicate::ULT, idx, arr.len, "in_bounds")?;
+            let fmt = format!(
+                "index %lld out of bounds for {} of length %lld at {}",
+                what.replace('%', "%%"),
+                self.location(line)
+            );
+            self.build_runtime_check(in_bounds, &fmt, &[idx.into(), arr.len.into()])?;
+        }
+        self.element_ptr(arr, idx)
+    }
+
+    /// Every array value, wherever it lives, is a `{ ptr, i64 len }` pair.
+    fn array_struct_type(&self) -> StructType<'ctx> {
+        self.context.struct_type(
+            &[
+                self.context.ptr_type(AddressSpace::default()).into(),
+                self.context.i64_type().into(),
+            ],
+            false,
+        )
+    }
+
+    /// Take apart an array value whose elements have type `elem`.
+    fn array_ref(
+        &self,
+        val: StructValue<'ctx>,
+        elem: &Type,
+    ) -> Result<ArrayRef<'ctx>, CompileError> {
+        let ptr = self
+            .builder
+            .build_extract_value(val, 0, "array_ptr")?
+            .into_pointer_value();
+        let len = self
+            .builder
+            .build_extract_value(val, 1, "array_len")?
+            .into_int_value();
+        Ok(ArrayRef {
+            ptr,
+            len,
+            elem: elem.clone(),
+        })
+    }
+
+    fn int_kind_type(&self, kind: IntKind) -> IntType<'ctx> {
+        match kind.bits() {
+            8 => self.context.i8_type(),
+            16 => self.context.i16_type(),
+            32 => self.context.i32_type(),
+            _ => self.context.i64_type(),
+        }
+    }
+
+    /// Compile an index, widened to `int` from whatever integer type it has.
+    fn compile_index(&mut self, index: &Expr) -> Result<IntValue<'ctx>, CompileError> {
+        let signed = match self.expr_type(index) {
+            Type::Int(kind) => kind.signed(),
+            ty => {
+                return Err(CompileError::Codegen(format!(
+                    "An index must be an integer, not {}",
+                    ty
+                )));
+            }
+        };
+        let idx = self.compile_expr(index)?;
+        Ok(self
+            .builder
+            .build_int_cast_sign_flag(idx, self.int_type, signed, "idx")?)
+    }
+
+    /// The printf conversion for an integer of type `kind`, and the value to pass
+    /// for it: varargs narrower than C's `int` have to be promoted by hand.
+    fn printf_int(
+        &self,
+        val: IntValue<'ctx>,
+        kind: IntKind,
+    ) -> Result<(&'static str, IntValue<'ctx>), CompileError> {
+        let spec = match kind {
+            IntKind::I8 | IntKind::I16 | IntKind::I32 => "%d",
+            IntKind::I64 => "%lld",
+            IntKind::U8 | IntKind::U32 => "%u",
+            IntKind::U64 => "%llu",
+        };
+        let val = if kind.bits() < 32 {
+            self.builder
+                .build_int_cast_sign_flag(val, self.i32_type, kind.signed(), "promoted")?
+        } else {
+            val
+        };
+        Ok((spec, val))
+    }
+
+    /// The LLVM type used to store a value of type `ty` in memory.
+    fn llvm_type(&self, ty: &Type) -> BasicTypeEnum<'ctx> {
+        match ty {
+            Type::Int(kind) => self.int_kind_type(*kind).into(),
+            Type::Bool => self.context.bool_type().into(),
+            Type::Array(_) => self.array_struct_type().into(),
+            Type::List(_) | Type::Str => self.context.ptr_type(AddressSpace::default()).into(),
+            Type::Tuple(elems) => {
+                let fields: Vec<BasicTypeEnum> = elems.iter().map(|t| self.llvm_type(t)).collect();
+                self.context.struct_type(&fields, false).into()
+            }
+            Type::Param(name) => unreachable!("type parameter {} was not substituted", name),
+        }
+    }
+
+    fn array_struct_value(&self, arr: &ArrayRef<'ctx>) -> Result<StructValue<'ctx>, CompileError> {
+        let undef = self.array_struct_type().get_undef();
+        let with_ptr = self
+            .builder
+            .build_insert_value(undef, arr.ptr, 0, "row_ptr")?;
+        let with_len = self
+            .builder
+            .build_insert_value(with_ptr, arr.len, 1, "row_len")?;
+        Ok(with_len.into_struct_value())
+    }
+
+    /// Compile an expression into the value that would be stored for it in memory.
+    fn compile_value(&mut self, expr: &Expr) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        match self.expr_type(expr) {
+            Type::Int(_) | Type::Bool => Ok(self.compile_expr(expr)?.into()),
+            Type::Array(_) => {
+                let arr = self.compile_array(expr)?;
+                Ok(self.array_struct_value(&arr)?.into())
+            }
+            Type::List(_) => Ok(self.compile_list(expr)?.into()),
+            Type::Str => Ok(self.compile_string(expr)?.into()),
+            Type::Tuple(_) => Ok(self.compile_tuple(expr)?.into()),
+            Type::Param(name) => Err(CompileError::Codegen(format!(
+                "Type parameter {} is not known here",
+                name
+            ))),
+        }
+    }
+
+    /// The type an expression evaluates to, as the type checker worked it out.
+    fn expr_type(&self, expr: &Expr) -> Type {
+        expr.ty
+            .clone()
+            .expect("the type checker annotates every expression")
+    }
+
+    fn compile_expr(&mut self, expr: &Expr) -> Result<IntValue<'ctx>, CompileError> {
+        match &expr.kind {
+            ExprKind::Number(n, kind) => Ok(self
+                .int_kind_type(*kind)
+                .const_int(*n as u64, kind.signed())),
+            ExprKind::Bool(b) => Ok(self.context.bool_type().const_int(*b as u64, false)),
+            ExprKind::StrLiteral(_) => Err(CompileError::Codegen(
+                "A string cannot be used as a number".into(),
+            )),
+            ExprKind::Variable(name) => {
+                if let Some(ty @ (Type::Array(_) | Type::List(_) | Type::Str | Type::Tuple(_))) =
+                    self.var_types.get(name)
+                {
+                    return Err(CompileError::Codegen(format!(
+                        "{} is {} and cannot be used as a number",
+                        name, ty
+                    )));
+                }
+                Ok(self.load_variable(name)?.into_int_value())
+            }
+            ExprKind::Unary { op, expr, line } => {
+                let v = self.compile_expr(expr)?;
+                match op {
+                    UnOp::Pos => Ok(v),
+                    UnOp::Neg => match self.expr_type(expr) {
+                        Type::Int(kind) if self.overflow_checks => {
+                            let zero = v.get_type().const_zero();
+                            self.build_checked_arith(BinOp::Sub, zero, v, kind, *line)
+                        }
+                        _ => Ok(self.builder.build_int_neg(v, "negtmp")?),
+                    },
+                    UnOp::Not => Ok(self.builder.build_not(v, "nottmp")?),
+                }
+            }
+            ExprKind::Binary {
+                op: op @ (BinOp::And | BinOp::Or),
+                left,
+                right,
+                ..
+            } => self.compile_logical(*op, left, right),
+            ExprKind::Binary {
+                op, left, right, ..
+            } if self.expr_type(left) == Type::Str => self.compile_string_compare(*op, left, right),
+            ExprKind::Binary {
+                op,
+                left,
+                right,
+                line,
+            } => {
+                let ty = self.expr_type(left);
+                let kind = match ty {
+                    Type::Int(kind) => Some(kind),
+                    Type::Bool if matches!(op, BinOp::Eq | BinOp::Ne) => None,
+                    _ => {
+                        return Err(CompileError::Codegen(format!(
+                            "Operator {:?} is not supported for {}",
+                            op, ty
+                        )));
+                    }
+                };
+                let l = self.compile_expr(left)?;
+                let r = self.compile_expr(right)?;
+                if self.overflow_checks
+                    && let Some(kind) = kind
+                {
+                    match op {
+                        BinOp::Add | BinOp::Sub | BinOp::Mul => {
+                            return self.build_checked_arith(*op, l, r, kind, *line);
+                        }
+                        BinOp::Div | BinOp::Rem => self.build_division_check(l, r, kind, *line)?,
+                        _ => {}
+                    }
+                }
+                let signed = kind.is_some_and(IntKind::signed);
+                let pred = |s, u| if signed { s } else { u };
+                let rv = match op {
+                    BinOp::Add => self.builder.build_int_add(l, r, "addtmp")?,
+                    BinOp::Sub => self.builder.build_int_sub(l, r, "subtmp")?,
+                    BinOp::Mul => self.builder.build_int_mul(l, r, "multmp")?,
+                    BinOp::Div if signed => self.builder.build_int_signed_div(l, r, "divtmp")?,
+                    BinOp::Div => self.builder.build_int_unsigned_div(l, r, "divtmp")?,
+                    BinOp::Rem if signed => self.builder.build_int_signed_rem(l, r, "remtmp")?,
+                    BinOp::Rem => self.builder.build_int_unsigned_rem(l, r, "remtmp")?,
+                    BinOp::Lt => self.builder.build_int_compare(
+                        pred(IntPredicate::SLT, IntPredicate::ULT),
+                        l,
+                        r,
+                        "lttmp",
+                    )?,
+                    BinOp::Le => self.builder.build_int_compare(
+                        pred(IntPredicate::SLE, IntPredicate::ULE),
+                        l,
+                        r,
+                        "letmp",
+                    )?,
+                    BinOp::Gt => self.builder.build_int_compare(
+                        pred(IntPredicate::SGT, IntPredicate::UGT),
+                        l,
+                        r,
+                        "gttmp",
+                    )?,
+                    BinOp::Ge => self.builder.build_int_compare(
+                        pred(IntPredicate::SGE, IntPredicate::UGE),
+                        l,
+                        r,
+                        "getmp",
+                    )?,
+                    BinOp::Eq => self
+                        .builder
+                        .build_int_compare(IntPredicate::EQ, l, r, "eqtmp")?,
+                    BinOp::Ne => self
+                        .builder
+                        .build_int_compare(IntPredicate::NE, l, r, "netmp")?,
+                    BinOp::And | BinOp::Or => unreachable!("logical operators are compiled above"),
+                };
+                Ok(rv)
+            }
+            ExprKind::Call { name, args } => {
+                if name == "length" && args.len() == 1 {
+                    return self.compile_length(&args[0]);
+                }
+
+                if name == "list" {
+                    return Err(CompileError::Codegen(
+                        "A list cannot be used as a number".into(),
+                    ));
+                }
+                if name == "parse_int" {
+                    if args.len() != 1 {
                         return Err(CompileError::Codegen(
-                            "Length must be called on a variable".into(),
+                            "parse_int() takes a single string".into(),
                         ));
                     }
+                    let s = self.compile_string(&args[0])?;
+                    let parsed = self
+                        .builder
+                        .build_call(self.libc_fn("atoll"), &[s.into()], "parsed")?
+                        .try_as_basic_value()
+                        .left()
+                        .unwrap();
+                    return Ok(parsed.into_int_value());
+                }
+                if let Some(op) = wrapping_op(name) {
+                    if args.len() != 2 {
+                        return Err(CompileError::Codegen(format!(
+                            "{}() takes two integers",
+                            name
+                        )));
+                    }
+                    let l = self.compile_expr(&args[0])?;
+                    let r = self.compile_expr(&args[1])?;
+                    let rv = match op {
+                        BinOp::Add => self.builder.build_int_add(l, r, "addtmp")?,
+                        BinOp::Sub => self.builder.build_int_sub(l, r, "subtmp")?,
+                        _ => self.builder.build_int_mul(l, r, "multmp")?,
+                    };
+                    return Ok(rv);
+                }
+
+                let return_type = self.expr_type(expr);
+                let val = self.compile_call(name, args)?;
+                match return_type {
+                    Type::Array(_) | Type::List(_) | Type::Str | Type::Tuple(_) => {
+                        Err(CompileError::Codegen(format!(
+                            "{} returns {}, which cannot be used as a number",
+                            name, return_type
+                        )))
+                    }
+                    Type::Int(_) | Type::Bool | Type::Param(_) => Ok(val.into_int_value()),
+                }
+            }
+            ExprKind::ArrayLiteral(_) => Err(CompileError::Codegen(
+                "An array cannot be used as a number".into(),
+            )),
+            ExprKind::Index { array, index, line } => {
+                if !matches!(self.expr_type(expr), Type::Int(_) | Type::Bool) {
+                    return Err(CompileError::Codegen(format!(
+                        "Element of type {} cannot be used as a number",
+                        self.expr_type(expr)
+                    )));
+                }
+                Ok(self.compile_element(array, index, *line)?.into_int_value())
+            }
+            ExprKind::Length { array } => self.compile_length(array),
+            ExprKind::MethodCall {
+                receiver,
+                method,
+                args,
+                line,
+            } => Ok(self
+                .compile_method_call(receiver, method, args, *line)?
+                .into_int_value()),
+            ExprKind::Tuple(_) => Err(CompileError::Codegen(
+                "A tuple cannot be used as a number".into(),
+            )),
+            ExprKind::Cast { expr, to } => {
+                let signed = match self.expr_type(expr) {
+                    Type::Int(kind) => kind.signed(),
+                    Type::Bool => false,
+                    ty => {
+                        return Err(CompileError::Codegen(format!(
+                            "Cannot cast {} to {}",
+                            ty, to
+                        )));
+                    }
                 };
-                let size_ptr = self
-                    .variables
-                    .get(&format!("{}_size", array_name))
-                    .ok_or_else(|| {
-                        CompileError::Codegen(format!("undefined array size for {}", array_name))
-                    })?;
-                let size = self
+                let v = self.compile_expr(expr)?;
+                Ok(self.builder.build_int_cast_sign_flag(
+                    v,
+                    self.int_kind_type(*to),
+                    signed,
+                    "cast",
+                )?)
+            }
+        }
+    }
+
+    /// The length of an array, list or string.
+    fn compile_length(&mut self, array: &Expr) -> Result<IntValue<'ctx>, CompileError> {
+        match self.expr_type(array) {
+            Type::List(_) => {
+                let list = self.compile_list(array)?;
+                self.load_list_len(list)
+            }
+            Type::Str => {
+                let s = self.compile_string(array)?;
+                self.build_strlen(s)
+            }
+            _ => Ok(self.compile_array(array)?.len),
+        }
+    }
+
+    /// Call a user-defined function.
+    fn compile_call(
+        &mut self,
+        name: &str,
+        args: &[Expr],
+    ) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        let fn_val = self
+            .module
+            .get_function(&symbol(name))
+            .ok_or_else(|| CompileError::Codegen(format!("unknown fn {}", name)))?;
+        let mut compiled_args = Vec::new();
+        for arg in args {
+            compiled_args.push(self.compile_value(arg)?.into());
+        }
+        let call_site = self.builder.build_call(fn_val, &compiled_args, "calltmp")?;
+        call_site
+            .try_as_basic_value()
+            .left()
+            .ok_or_else(|| CompileError::Codegen(format!("{} does not return a value", name)))
+    }
+
+    /// Compile an expression that evaluates to a list, yielding a pointer to its header.
+    fn compile_list(&mut self, expr: &Expr) -> Result<PointerValue<'ctx>, CompileError> {
+        match &expr.kind {
+            ExprKind::Call { name, args } if name == "list" => {
+                if !args.is_empty() {
+                    return Err(CompileError::Codegen("list() takes no arguments".into()));
+                }
+                let header = self.list_struct_type();
+                let size = header
+                    .size_of()
+                    .ok_or_else(|| CompileError::Codegen("list header has no size".into()))?;
+                let list = self
                     .builder
-                    .build_load(self.i32_type, *size_ptr, "load_size")?;
-                Ok(size.into_int_value())
+                    .build_call(self.libc_fn("malloc"), &[size.into()], "list")?
+                    .try_as_basic_value()
+                    .left()
+                    .unwrap()
+                    .into_pointer_value();
+                self.builder.build_store(list, header.const_zero())?;
+                Ok(list)
+            }
+            ExprKind::Call { name, args } => {
+                Ok(self.compile_call(name, args)?.into_pointer_value())
+            }
+            ExprKind::Variable(name) => Ok(self.load_variable(name)?.into_pointer_value()),
+            ExprKind::Index { array, index, line } => Ok(self
+                .compile_element(array, index, *line)?
+                .into_pointer_value()),
+            ExprKind::MethodCall {
+                receiver,
+                method,
+                args,
+                line,
+            } => Ok(self
+                .compile_method_call(receiver, method, args, *line)?
+                .into_pointer_value()),
+            _ => Err(CompileError::Codegen("Expected a list".into())),
+        }
+    }
+
+    /// Compile an expression that evaluates to a tuple, yielding it as an LLVM struct value.
+    fn compile_tuple(&mut self, expr: &Expr) -> Result<StructValue<'ctx>, CompileError> {
+        match &expr.kind {
+            ExprKind::Tuple(elems) => {
+                let ty = self.llvm_type(&self.expr_type(expr)).into_struct_type();
+                let mut tuple = ty.get_undef();
+                for (i, e) in elems.iter().enumerate() {
+                    let val = self.compile_value(e)?;
+                    tuple = self
+                        .builder
+                        .build_insert_value(tuple, val, i as u32, "tuple")?
+                        .into_struct_value();
+                }
+                Ok(tuple)
             }
+            ExprKind::Variable(name) => Ok(self.load_variable(name)?.into_struct_value()),
+            ExprKind::Index { array, index, line } => Ok(self
+                .compile_element(array, index, *line)?
+                .into_struct_value()),
+            ExprKind::Call { name, args } => Ok(self.compile_call(name, args)?.into_struct_value()),
+            ExprKind::MethodCall {
+                receiver,
+                method,
+                args,
+                line,
+            } => Ok(self
+                .compile_method_call(receiver, method, args, *line)?
+                .into_struct_value()),
+            _ => Err(CompileError::Codegen(format!(
+                "Expected a tuple, found {}",
+                self.expr_type(expr)
+            ))),
+        }
+    }
+
+    /// Load element `index` of an array or list.
+    fn compile_element(
+        &mut self,
+        array: &Expr,
+        index: &Expr,
+        line: usize,
+    ) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        if let Type::List(elem) = self.expr_type(array) {
+            let list = self.compile_list(array)?;
+            let idx = self.compile_index(index)?;
+            let ptr = self.list_element_ptr(list, &elem, idx, line)?;
+            return Ok(self
+                .builder
+                .build_load(self.llvm_type(&elem), ptr, "list_load")?);
         }
+        let arr = self.compile_array(array)?;
+        let idx = self.compile_index(index)?;
+        let ptr = self.checked_element_ptr(&arr, idx, array, line)?;
+        Ok(self
+            .builder
+            .build_load(self.llvm_type(&arr.elem), ptr, "index_load")?)
     }
 
-    fn build_int_cmp(
+    /// Compile an expression that evaluates to a string, yielding a pointer to its bytes.
+    fn compile_string(&mut self, expr: &Expr) -> Result<PointerValue<'ctx>, CompileError> {
+        match &expr.kind {
+            ExprKind::StrLiteral(s) => {
+                let gs = self
+                    .builder
+                    .build_global_string_ptr(&format!("{}\0", s), "strlit")?;
+                Ok(gs.as_pointer_value())
+            }
+            ExprKind::Variable(name) => Ok(self.load_variable(name)?.into_pointer_value()),
+            ExprKind::Binary {
+                op: BinOp::Add,
+                left,
+                right,
+                ..
+            } => {
+                let l = self.compile_string(left)?;
+                let r = self.compile_string(right)?;
+                self.build_concat(l, r)
+            }
+            ExprKind::Index { array, index, line } if self.expr_type(array) == Type::Str => {
+                let s = self.compile_string(array)?;
+                let idx = self.compile_index(index)?;
+                if self.bounds_checks {
+                    let len = self.build_strlen(s)?;
+                    let in_bounds =
+                        self.builder
+                            .build_int_compare(IntPredicate::ULT, idx, len, "in_bounds")?;
+                    let fmt = format!(
+                        "string index %lld out of bounds for length %lld at {}",
+                        self.location(*line)
+                    );
+                    self.build_runtime_check(in_bounds, &fmt, &[idx.into(), len.into()])?;
+                }
+                let i8_type = self.context.i8_type();
+                let src = unsafe {
+                    self.builder
+                        .build_in_bounds_gep(i8_type, s, &[idx], "char_ptr")?
+                };
+                let byte = self.builder.build_load(i8_type, src, "char")?;
+                let buf = self.build_malloc_bytes(self.context.i64_type().const_int(2, false))?;
+                self.builder.build_store(buf, byte)?;
+                let end = unsafe {
+                    self.builder.build_in_bounds_gep(
+                        i8_type,
+                        buf,
+                        &[self.i32_type.const_int(1, false)],
+                        "char_end",
+                    )?
+                };
+                self.builder.build_store(end, i8_type.const_int(0, false))?;
+                Ok(buf)
+            }
+            ExprKind::Index { array, index, line } => Ok(self
+                .compile_element(array, index, *line)?
+                .into_pointer_value()),
+            ExprKind::Call { name, args } if name == "to_string" => {
+                let [arg] = args.as_slice() else {
+                    return Err(CompileError::Codegen(
+                        "to_string() takes a single number".into(),
+                    ));
+                };
+                let Type::Int(kind) = self.expr_type(arg) else {
+                    return Err(CompileError::Codegen(
+                        "to_string() takes a single number".into(),
+                    ));
+                };
+                let n = self.compile_expr(arg)?;
+                let (spec, n) = self.printf_int(n, kind)?;
+                // Enough for "-9223372036854775808" and the terminator
+                let size = self.context.i64_type().const_int(21, false);
+                let buf = self.build_malloc_bytes(size)?;
+                let fmt = self
+                    .builder
+                    .build_global_string_ptr(&format!("{}\0", spec), "fmt")?;
+                self.builder.build_call(
+                    self.libc_fn("snprintf"),
+                    &[
+                        buf.into(),
+                        size.into(),
+                        fmt.as_pointer_value().into(),
+                        n.into(),
+                    ],
+                    "",
+                )?;
+                Ok(buf)
+            }
+            ExprKind::Call { name, args } => {
+                Ok(self.compile_call(name, args)?.into_pointer_value())
+            }
+            ExprKind::MethodCall {
+                receiver,
+                method,
+                args,
+                line,
+            } => Ok(self
+                .compile_method_call(receiver, method, args, *line)?
+                .into_pointer_value()),
+            _ => Err(CompileError::Codegen(format!(
+                "Expected a string, found {}",
+                self.expr_type(expr)
+            ))),
+        }
+    }
+
+    /// Compare two strings with `strcmp`; `+` is handled by `compile_string`.
+    fn compile_string_compare(
+        &mut self,
+        op: BinOp,
+        left: &Expr,
+        right: &Expr,
+    ) -> Result<IntValue<'ctx>, CompileError> {
+        let pred = match op {
+            BinOp::Lt => IntPredicate::SLT,
+            BinOp::Le => IntPredicate::SLE,
+            BinOp::Gt => IntPredicate::SGT,
+            BinOp::Ge => IntPredicate::SGE,
+            BinOp::Eq => IntPredicate::EQ,
+            BinOp::Ne => IntPredicate::NE,
+            _ => {
+                return Err(CompileError::Codegen(format!(
+                    "Operator {:?} is not supported for strings",
+                    op
+                )));
+            }
+        };
+        let l = self.compile_string(left)?;
+        let r = self.compile_string(right)?;
+        let cmp = self
+            .builder
+            .build_call(self.libc_fn("strcmp"), &[l.into(), r.into()], "strcmp")?
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value();
+        let zero = self.i32_type.const_int(0, false);
+        Ok(self
+            .builder
+            .build_int_compare(pred, cmp, zero, "strcmptmp")?)
+    }
+
+    /// Compile `&&` or `||`, skipping the right side when the left decides the result.
+    fn compile_logical(
+        &mut self,
+        op: BinOp,
+        left: &Expr,
+        right: &Expr,
+    ) -> Result<IntValue<'ctx>, CompileError> {
+        let l = self.compile_expr(left)?;
+        let lhs_bb = self.builder.get_insert_block().unwrap();
+        let parent = lhs_bb.get_parent().unwrap();
+        let rhs_bb = self.context.append_basic_block(parent, "rhs");
+        let done_bb = self.context.append_basic_block(parent, "logic_done");
+        if let BinOp::And = op {
+            self.builder.build_conditional_branch(l, rhs_bb, done_bb)?;
+        } else {
+            self.builder.build_conditional_branch(l, done_bb, rhs_bb)?;
+        }
+
+        self.builder.position_at_end(rhs_bb);
+        let r = self.compile_expr(right)?;
+        // The right side may have branched itself
+        let rhs_end = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(done_bb)?;
+
+        self.builder.position_at_end(done_bb);
+        let phi = self
+            .builder
+            .build_phi(self.context.bool_type(), "logictmp")?;
+        phi.add_incoming(&[(&l, lhs_bb), (&r, rhs_end)]);
+        Ok(phi.as_basic_value().into_int_value())
+    }
+
+    /// Allocate a fresh string holding `l` followed by `r`.
+    fn build_concat(
+        &mut self,
+        l: PointerValue<'ctx>,
+        r: PointerValue<'ctx>,
+    ) -> Result<PointerValue<'ctx>, CompileError> {
+        let i64_type = self.context.i64_type();
+        let l_len = self.build_strlen(l)?;
+        let r_len = self.build_strlen(r)?;
+        let r_size = self
+            .builder
+            .build_int_add(r_len, i64_type.const_int(1, false), "r_size")?;
+        let total = self.builder.build_int_add(l_len, r_size, "total")?;
+        let buf = self.build_malloc_bytes(total)?;
+        self.builder.build_memcpy(buf, 1, l, 1, l_len)?;
+        let tail = unsafe {
+            self.builder
+                .build_in_bounds_gep(self.context.i8_type(), buf, &[l_len], "tail")?
+        };
+        // Copies the terminator along with `r`
+        self.builder.build_memcpy(tail, 1, r, 1, r_size)?;
+        Ok(buf)
+    }
+
+    fn build_strlen(&self, s: PointerValue<'ctx>) -> Result<IntValue<'ctx>, CompileError> {
+        Ok(self
+            .builder
+            .build_call(self.libc_fn("strlen"), &[s.into()], "strlen")?
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_int_value())
+    }
+
+    fn build_malloc_bytes(&self, size: IntValue<'ctx>) -> Result<PointerValue<'ctx>, CompileError> {
+        Ok(self
+            .builder
+            .build_call(self.libc_fn("malloc"), &[size.into()], "buf")?
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_pointer_value())
+    }
+
+    fn compile_method_call(
+        &mut self,
+        receiver: &Expr,
+        method: &str,
+        args: &[Expr],
+        line: usize,
+    ) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        let Type::List(elem) = self.expr_type(receiver) else {
+            return Err(CompileError::Codegen(format!(
+                "Method {} is only available on lists",
+                method
+            )));
+        };
+        let expected_args = match method {
+            "push" => 1,
+            "pop" | "clear" => 0,
+            "insert" => 2,
+            "remove" => 1,
+            _ => {
+                return Err(CompileError::Codegen(format!(
+                    "Unknown list method {}",
+                    method
+                )));
+            }
+        };
+        if args.len() != expected_args {
+            return Err(CompileError::Codegen(format!(
+                "{}() takes {} argument(s), got {}",
+                method,
+                expected_args,
+                args.len()
+            )));
+        }
+
+        let list = self.compile_list(receiver)?;
+        let elem_type = self.llvm_type(&elem);
+        let elem_size = elem_type
+            .size_of()
+            .ok_or_else(|| CompileError::Codegen("list element has no size".into()))?;
+        let one = self.int_type.const_int(1, false);
+        let unit = self.int_type.const_int(0, false).into();
+        match method {
+            "push" => {
+                let val = self.compile_value(&args[0])?;
+                self.build_list_grow(list, elem_size)?;
+                let len = self.load_list_len(list)?;
+                let ptr = self.list_data_ptr(list, elem_type, len)?;
+                self.builder.build_store(ptr, val)?;
+                let new_len = self.builder.build_int_add(len, one, "new_len")?;
+                self.builder
+                    .build_store(self.list_field_ptr(list, LIST_LEN)?, new_len)?;
+                Ok(unit)
+            }
+            "pop" => {
+                let len = self.load_list_len(list)?;
+                let non_empty = self.builder.build_int_compare(
+                    IntPredicate::SGT,
+                    len,
+                    self.int_type.const_int(0, false),
+                    "non_empty",
+                )?;
+                let fmt = format!("pop from an empty list at {}", self.location(line));
+                self.build_runtime_check(non_empty, &fmt, &[])?;
+                let new_len = self.builder.build_int_sub(len, one, "new_len")?;
+                self.builder
+                    .build_store(self.list_field_ptr(list, LIST_LEN)?, new_len)?;
+                let ptr = self.list_data_ptr(list, elem_type, new_len)?;
+                Ok(self.builder.build_load(elem_type, ptr, "popped")?)
+            }
+            "insert" => {
+                let idx = self.compile_index(&args[0])?;
+                let val = self.compile_value(&args[1])?;
+                let len = self.load_list_len(list)?;
+                let in_bounds =
+                    self.builder
+                        .build_int_compare(IntPredicate::ULE, idx, len, "in_bounds")?;
+                let fmt = format!(
+                    "insert index %lld out of bounds for list of length %lld at {}",
+                    self.location(line)
+                );
+                self.build_runtime_check(in_bounds, &fmt, &[idx.into(), len.into()])?;
+                self.build_list_grow(list, elem_size)?;
+                let slot = self.list_data_ptr(list, elem_type, idx)?;
+                let next = self.list_data_ptr(
+                    list,
+                    elem_type,
+                    self.builder.build_int_add(idx, one, "next_idx")?,
+                )?;
+                let tail = self.builder.build_int_sub(len, idx, "tail_len")?;
+                let bytes = self.list_byte_count(tail, elem_size)?;
+                self.builder.build_memmove(next, 1, slot, 1, bytes)?;
+                self.builder.build_store(slot, val)?;
+                let new_len = self.builder.build_int_add(len, one, "new_len")?;
+                self.builder
+                    .build_store(self.list_field_ptr(list, LIST_LEN)?, new_len)?;
+                Ok(unit)
+            }
+            "remove" => {
+                let idx = self.compile_index(&args[0])?;
+                let len = self.load_list_len(list)?;
+                let in_bounds =
+                    self.builder
+                        .build_int_compare(IntPredicate::ULT, idx, len, "in_bounds")?;
+                let fmt = format!(
+                    "remove index %lld out of bounds for list of length %lld at {}",
+                    self.location(line)
+                );
+                self.build_runtime_check(in_bounds, &fmt, &[idx.into(), len.into()])?;
+                let slot = self.list_data_ptr(list, elem_type, idx)?;
+                let removed = self.builder.build_load(elem_type, slot, "removed")?;
+                let next = self.list_data_ptr(
+                    list,
+                    elem_type,
+                    self.builder.build_int_add(idx, one, "next_idx")?,
+                )?;
+                let new_len = self.builder.build_int_sub(len, one, "new_len")?;
+                let tail = self.builder.build_int_sub(new_len, idx, "tail_len")?;
+                let bytes = self.list_byte_count(tail, elem_size)?;
+                self.builder.build_memmove(slot, 1, next, 1, bytes)?;
+                self.builder
+                    .build_store(self.list_field_ptr(list, LIST_LEN)?, new_len)?;
+                Ok(removed)
+            }
+            _ => {
+                // clear: keep the buffer around for reuse
+                self.builder.build_store(
+                    self.list_field_ptr(list, LIST_LEN)?,
+                    self.int_type.const_int(0, false),
+                )?;
+                Ok(unit)
+            }
+        }
+    }
+
+    /// Lists are a pointer to a heap-allocated `{ ptr data, i64 len, i64 cap }` header.
+    fn list_struct_type(&self) -> StructType<'ctx> {
+        self.context.struct_type(
+            &[
+                self.context.ptr_type(AddressSpace::default()).into(),
+                self.int_type.into(),
+                self.int_type.into(),
+            ],
+            false,
+        )
+    }
+
+    fn list_field_ptr(
+        &self,
+        list: PointerValue<'ctx>,
+        field: u32,
+    ) -> Result<PointerValue<'ctx>, CompileError> {
+        Ok(self
+            .builder
+            .build_struct_gep(self.list_struct_type(), list, field, "list_field")?)
+    }
+
+    fn load_list_len(&self, list: PointerValue<'ctx>) -> Result<IntValue<'ctx>, CompileError> {
+        let len_ptr = self.list_field_ptr(list, LIST_LEN)?;
+        Ok(self
+            .builder
+            .build_load(self.int_type, len_ptr, "list_len")?
+            .into_int_value())
+    }
+
+    /// Pointer to slot `idx` of the list's buffer, without any bounds check.
+    fn list_data_ptr(
         &self,
-        pred: IntPredicate,
+        list: PointerValue<'ctx>,
+        elem_type: BasicTypeEnum<'ctx>,
+        idx: IntValue<'ctx>,
+    ) -> Result<PointerValue<'ctx>, CompileError> {
+        let data_ptr = self.list_field_ptr(list, LIST_DATA)?;
+        let data = self
+            .builder
+            .build_load(
+                self.context.ptr_type(AddressSpace::default()),
+                data_ptr,
+                "list_data",
+            )?
+            .into_pointer_value();
+        let ptr = unsafe {
+            self.builder
+                .build_in_bounds_gep(elem_type, data, &[idx], "list_elem")?
+        };
+        Ok(ptr)
+    }
+
+    /// Pointer to element `idx` of a list, aborting at runtime if it is out of bounds
+    /// unless bounds checks are turned off.
+    fn list_element_ptr(
+        &mut self,
+        list: PointerValue<'ctx>,
+        elem: &Type,
+        idx: IntValue<'ctx>,
+        line: usize,
+    ) -> Result<PointerValue<'ctx>, CompileError> {
+        if self.bounds_checks {
+            let len = self.load_list_len(list)?;
+            let in_bounds =
+                self.builder
+                    .build_int_compare(IntPredicate::ULT, idx, len, "in_bounds")?;
+            let fmt = format!(
+                "list index %lld out of bounds for length %lld at {}",
+                self.location(line)
+            );
+            self.build_runtime_check(in_bounds, &fmt, &[idx.into(), len.into()])?;
+        }
+        self.list_data_ptr(list, self.llvm_type(elem), idx)
+    }
+
+    fn list_byte_count(
+        &self,
+        count: IntValue<'ctx>,
+        elem_size: IntValue<'ctx>,
+    ) -> Result<IntValue<'ctx>, CompileError> {
+        Ok(self.builder.build_int_mul(count, elem_size, "bytes")?)
+    }
+
+    fn build_list_grow(
+        &mut self,
+        list: PointerValue<'ctx>,
+        elem_size: IntValue<'ctx>,
+    ) -> Result<(), CompileError> {
+        let grow_fn = self.list_grow_fn()?;
+        self.builder
+            .build_call(grow_fn, &[list.into(), elem_size.into()], "")?;
+        Ok(())
+    }
+
+    /// `toy_list_grow(list, elem_size)` makes room for one more element, doubling the
+    /// capacity with `realloc` whenever the buffer is full.
+    fn list_grow_fn(&mut self) -> Result<FunctionValue<'ctx>, CompileError> {
+        if let Some(f) = self.module.get_function("toy_list_grow") {
+            return Ok(f);
+        }
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let fn_type = self
+            .context
+            .void_type()
+            .fn_type(&[ptr_type.into(), self.context.i64_type().into()], false);
+        let function = self
+            .module
+            .add_function("toy_list_grow", fn_type, Some(Linkage::Internal));
+        let saved = self.builder.get_insert_block();
+
+        let entry = self.context.append_basic_block(function, "entry");
+        let grow_bb = self.context.append_basic_block(function, "grow");
+        let done_bb = self.context.append_basic_block(function, "done");
+        self.builder.position_at_end(entry);
+        let list = function.get_nth_param(0).unwrap().into_pointer_value();
+        let elem_size = function.get_nth_param(1).unwrap().into_int_value();
+        let len = self.load_list_len(list)?;
+        let cap_ptr = self.list_field_ptr(list, LIST_CAP)?;
+        let cap = self
+            .builder
+            .build_load(self.int_type, cap_ptr, "cap")?
+            .into_int_value();
+        let full = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, len, cap, "full")?;
+        self.builder
+            .build_conditional_branch(full, grow_bb, done_bb)?;
+
+        self.builder.position_at_end(grow_bb);
+        let zero = self.int_type.const_int(0, false);
+        let is_empty = self
+            .builder
+            .build_int_compare(IntPredicate::EQ, cap, zero, "is_empty")?;
+        let doubled =
+            self.builder
+                .build_int_mul(cap, self.int_type.const_int(2, false), "doubled")?;
+        let new_cap = self
+            .builder
+            .build_select(
+                is_empty,
+                self.int_type.const_int(4, false),
+                doubled,
+                "new_cap",
+            )?
+            .into_int_value();
+        let data_ptr = self.list_field_ptr(list, LIST_DATA)?;
+        let data = self.builder.build_load(ptr_type, data_ptr, "data")?;
+        let bytes = self.list_byte_count(new_cap, elem_size)?;
+        let new_data = self
+            .builder
+            .build_call(
+                self.libc_fn("realloc"),
+                &[data.into(), bytes.into()],
+                "new_data",
+            )?
+            .try_as_basic_value()
+            .left()
+            .unwrap();
+        self.builder.build_store(data_ptr, new_data)?;
+        self.builder.build_store(cap_ptr, new_cap)?;
+        self.builder.build_unconditional_branch(done_bb)?;
+
+        self.builder.position_at_end(done_bb);
+        self.builder.build_return(None)?;
+
+        if let Some(bb) = saved {
+            self.builder.position_at_end(bb);
+        }
+        Ok(function)
+    }
+
+    /// Declare (once) a C library function the generated code relies on.
+    fn libc_fn(&self, name: &str) -> FunctionValue<'ctx> {
+        if let Some(f) = self.module.get_function(name) {
+            return f;
+        }
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i64_type = self.context.i64_type();
+        let fn_type = match name {
+            "malloc" => ptr_type.fn_type(&[i64_type.into()], false),
+            "realloc" => ptr_type.fn_type(&[ptr_type.into(), i64_type.into()], false),
+            "dprintf" => self
+                .i32_type
+                .fn_type(&[self.i32_type.into(), ptr_type.into()], true),
+            "exit" => self
+                .context
+                .void_type()
+                .fn_type(&[self.i32_type.into()], false),
+            "strlen" => i64_type.fn_type(&[ptr_type.into()], false),
+            "strcmp" => self
+                .i32_type
+                .fn_type(&[ptr_type.into(), ptr_type.into()], false),
+            "snprintf" => self
+                .i32_type
+                .fn_type(&[ptr_type.into(), i64_type.into(), ptr_type.into()], true),
+            "atoll" => i64_type.fn_type(&[ptr_type.into()], false),
+            _ => unreachable!("unknown libc function {}", name),
+        };
+        self.module.add_function(name, fn_type, None)
+    }
+
+    /// Continue only if `ok` holds; otherwise print `runtime error: <fmt>` to stderr and exit.
+    fn build_runtime_check(
+        &mut self,
+        ok: IntValue<'ctx>,
+        fmt: &str,
+        args: &[BasicMetadataValueEnum<'ctx>],
+    ) -> Result<(), CompileError> {
+        let function = self
+            .builder
+            .get_insert_block()
+            .and_then(|bb| bb.get_parent())
+            .ok_or_else(|| CompileError::Codegen("runtime check outside a function".into()))?;
+        let fail_bb = self.context.append_basic_block(function, "check_fail");
+        let ok_bb = self.context.append_basic_block(function, "check_ok");
+        self.builder.build_conditional_branch(ok, ok_bb, fail_bb)?;
+
+        self.builder.position_at_end(fail_bb);
+        let fmt = self
+            .builder
+            .build_global_string_ptr(&format!("runtime error: {}\n\0", fmt), "errfmt")?;
+        let mut call_args: Vec<BasicMetadataValueEnum> = vec![
+            self.i32_type.const_int(2, false).into(),
+            fmt.as_pointer_value().into(),
+        ];
+        call_args.extend_from_slice(args);
+        self.builder
+            .build_call(self.libc_fn("dprintf"), &call_args, "")?;
+        self.builder.build_call(
+            self.libc_fn("exit"),
+            &[self.i32_type.const_int(1, false).into()],
+            "",
+        )?;
+        self.builder.build_unreachable()?;
+
+        self.builder.position_at_end(ok_bb);
+        Ok(())
+    }
+
+    /// `file:line`, escaped for use in a runtime check's format string.
+    fn location(&self, line: usize) -> String {
+        format!("{}:{}", self.file.replace('%', "%%"), line)
+    }
+
+    /// `l + r`, `l - r` or `l * r` through the `llvm.*.with.overflow` intrinsics,
+    /// aborting at runtime if the result does not fit in `kind`.
+    fn build_checked_arith(
+        &mut self,
+        op: BinOp,
         l: IntValue<'ctx>,
         r: IntValue<'ctx>,
-        name: &str,
+        kind: IntKind,
+        line: usize,
     ) -> Result<IntValue<'ctx>, CompileError> {
-        let b = self.builder.build_int_compare(pred, l, r, name)?;
-        let ext = self
+        let (name, what) = match op {
+            BinOp::Add => ("add", "addition"),
+            BinOp::Sub => ("sub", "subtraction"),
+            _ => ("mul", "multiplication"),
+        };
+        let sign = if kind.signed() { 's' } else { 'u' };
+        let intrinsic_name = format!("llvm.{}{}.with.overflow", sign, name);
+        let function = Intrinsic::find(&intrinsic_name)
+            .and_then(|i| i.get_declaration(&self.module, &[l.get_type().into()]))
+            .ok_or_else(|| CompileError::Codegen(format!("{} is not available", intrinsic_name)))?;
+        let result = self
+            .builder
+            .build_call(function, &[l.into(), r.into()], "checked")?
+            .try_as_basic_value()
+            .left()
+            .unwrap()
+            .into_struct_value();
+        let value = self
+            .builder
+            .build_extract_value(result, 0, &format!("{}tmp", name))?
+            .into_int_value();
+        let overflow = self
             .builder
-            .build_int_z_extend(b, self.i32_type, "bool2int")?;
-        Ok(ext)
+            .build_extract_value(result, 1, "overflow")?
+            .into_int_value();
+        let ok = self.builder.build_not(overflow, "no_overflow")?;
+        let fmt = format!("{} overflows {} at {}", what, kind, self.location(line));
+        self.build_runtime_check(ok, &fmt, &[])?;
+        Ok(value)
+    }
+
+    /// Abort at runtime if `l / r` or `l % r` is undefined: when `r` is zero, or when
+    /// the smallest value of a signed `kind` is divided by -1.
+    fn build_division_check(
+        &mut self,
+        l: IntValue<'ctx>,
+        r: IntValue<'ctx>,
+        kind: IntKind,
+        line: usize,
+    ) -> Result<(), CompileError> {
+        let ty = l.get_type();
+        let nonzero =
+            self.builder
+                .build_int_compare(IntPredicate::NE, r, ty.const_zero(), "nonzero")?;
+        let fmt = format!("division by zero at {}", self.location(line));
+        self.build_runtime_check(nonzero, &fmt, &[])?;
+        if kind.signed() {
+            let min = ty.const_int(1 << (kind.bits() - 1), false);
+            let is_min = self
+                .builder
+                .build_int_compare(IntPredicate::EQ, l, min, "is_min")?;
+            let is_minus_one = self.builder.build_int_compare(
+                IntPredicate::EQ,
+                r,
+                ty.const_all_ones(),
+                "is_minus_one",
+            )?;
+            let overflow = self.builder.build_and(is_min, is_minus_one, "overflow")?;
+            let ok = self.builder.build_not(overflow, "no_overflow")?;
+            let fmt = format!("division overflows {} at {}", kind, self.location(line));
+            self.build_runtime_check(ok, &fmt, &[])?;
+        }
+        Ok(())
+    }
+}
+
+/// The LLVM name of user function `name`. No toy identifier contains a `.`, so it
+/// cannot clash with `main`, the C library or the list runtime.
+fn symbol(name: &str) -> String {
+    format!("toy.{}", name)
+}
+
+/// The operator a `wrapping_add`, `wrapping_sub` or `wrapping_mul` call performs.
+fn wrapping_op(name: &str) -> Option<BinOp> {
+    match name {
+        "wrapping_add" => Some(BinOp::Add),
+        "wrapping_sub" => Some(BinOp::Sub),
+        "wrapping_mul" => Some(BinOp::Mul),
+        _ => None,
     }
 }
 
 impl Expr {
     fn array_len(&self) -> Option<usize> {
-        match self {
-            Expr::ArrayLiteral(elems) => Some(elems.len()),
+        match &self.kind {
+            ExprKind::ArrayLiteral(elems) => Some(elems.len()),
             _ => None,
         }
     }
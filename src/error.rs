@@ -8,6 +8,16 @@ pub enum CompileError {
     Io(String),
     Lex(String),
     Parse(String),
+    /// Every name resolution problem found in the program.
+    Resolve(Vec<String>),
+    /// Every function that can finish without returning its value, and every
+    /// variable that may be read before it is assigned.
+    Flow(Vec<String>),
+    /// Every warning raised by a lint set to deny.
+    Lint(Vec<String>),
+    Type(String),
+    /// Every constant expression that cannot be evaluated.
+    Fold(Vec<String>),
     Codegen(String),
 }
 
@@ -23,9 +33,25 @@ impl fmt::Display for CompileError {
             CompileError::Io(msg) => write!(f, "IO error: {}", msg),
             CompileError::Lex(msg) => write!(f, "Lexical error: {}", msg),
             CompileError::Parse(msg) => write!(f, "Parse error: {}", msg),
+            CompileError::Resolve(msgs) => write_all(f, "Resolve error", msgs),
+            CompileError::Flow(msgs) => write_all(f, "Flow error", msgs),
+            CompileError::Lint(msgs) => write_all(f, "Lint error", msgs),
+            CompileError::Type(msg) => write!(f, "Type error: {}", msg),
+            CompileError::Fold(msgs) => write_all(f, "Constant error", msgs),
             CompileError::Codegen(msg) => write!(f, "Codegen error: {}", msg),
         }
     }
 }
 
+/// One `<prefix>: <message>` line per message.
+fn write_all(f: &mut fmt::Formatter<'_>, prefix: &str, msgs: &[String]) -> fmt::Result {
+    for (i, msg) in msgs.iter().enumerate() {
+        if i > 0 {
+            writeln!(f)?;
+        }
+        write!(f, "{}: {}", prefix, msg)?;
+    }
+    Ok(())
+}
+
 impl Error for CompileError {}
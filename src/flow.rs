@@ -0,0 +1,344 @@
+use crate::ast::*;
+use crate::error::CompileError;
+use crate::lint::{Lint, Lints};
+use std::collections::{HashMap, HashSet};
+
+/// Check that every function which returns a value does so on every path, and that
+/// no variable declared without an initializer is read on a path that has not
+/// assigned it. Statements that can never run are reported to `lints`.
+pub fn check(prog: &Program, lints: &mut Lints) -> Result<(), CompileError> {
+    let mut flow = Flow {
+        scope: String::new(),
+        lints,
+        errors: Vec::new(),
+        scopes: Vec::new(),
+        decls: Vec::new(),
+        unassigned: HashSet::new(),
+        reported: HashSet::new(),
+    };
+    for func in &prog.functions {
+        flow.scope = format!("function {}", func.name);
+        let falls_through = flow.nested(&func.body);
+        let returns_value = func.return_type.is_some() || func.body.iter().any(contains_return);
+        if falls_through && returns_value {
+            flow.errors.push(format!(
+                "Function {} can reach its end without returning a value",
+                func.name
+            ));
+        }
+    }
+    flow.scope = "top-level code".into();
+    flow.nested(&prog.statements);
+
+    if flow.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(CompileError::Flow(flow.errors))
+    }
+}
+
+struct Flow<'l> {
+    scope: String, // where we are, for messages
+    lints: &'l mut Lints,
+    errors: Vec<String>,
+    scopes: Vec<HashMap<String, usize>>, // name -> declaration, innermost block last
+    decls: Vec<(String, usize)>,         // name and line of every declaration seen
+    unassigned: HashSet<usize>,          // declarations some path here has not assigned yet
+    reported: HashSet<usize>,            // declarations already reported as read too early
+}
+
+impl Flow<'_> {
+    /// Analyse a block whose declarations go out of scope at its end.
+    fn nested(&mut self, stmts: &[Statement]) -> bool {
+        self.scopes.push(HashMap::new());
+        let falls_through = self.block(stmts);
+        self.scopes.pop();
+        falls_through
+    }
+
+    /// Whether control can run off the end of `stmts`. Reports the first
+    /// unreachable statement, if any; the ones after it are not looked at.
+    fn block(&mut self, stmts: &[Statement]) -> bool {
+        for (i, stmt) in stmts.iter().enumerate() {
+            if !self.statement(stmt) {
+                if i + 1 < stmts.len() {
+                    let msg = format!(
+                        "Unreachable code after line {} in {}",
+                        line(stmt),
+                        self.scope
+                    );
+                    self.lints.report(Lint::UnreachableCode, msg);
+                }
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Whether control can continue to the statement after `stmt`.
+    fn statement(&mut self, stmt: &Statement) -> bool {
+        match stmt {
+            Statement::VarDecl {
+                name, expr, line, ..
+            } => {
+                if let Some(expr) = expr {
+                    self.read(expr);
+                }
+                self.declare(name, *line, expr.is_some());
+                true
+            }
+            Statement::LetDecl {
+                name, expr, line, ..
+            } => {
+                self.read(expr);
+                self.declare(name, *line, true);
+                true
+            }
+            Statement::LetPattern {
+                pattern,
+                expr,
+                line,
+            } => {
+                self.read(expr);
+                for name in pattern.names() {
+                    self.declare(name, *line, true);
+                }
+                true
+            }
+            Statement::Assign { name, expr, .. } => {
+                self.read(expr);
+                if let Some(id) = self.lookup(name) {
+                    self.unassigned.remove(&id);
+                }
+                true
+            }
+            Statement::IndexedAssign {
+                array, index, expr, ..
+            } => {
+                self.read(array);
+                self.read(index);
+                self.read(expr);
+                true
+            }
+            Statement::Print { expr } | Statement::ExprStmt(expr) => {
+                self.read(expr);
+                true
+            }
+            Statement::Return { expr, .. } => {
+                self.read(expr);
+                false
+            }
+            Statement::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.read(cond);
+                let before = self.unassigned.clone();
+                let then_falls = self.nested(then_branch);
+                let after_then = std::mem::replace(&mut self.unassigned, before);
+                let else_falls = match else_branch {
+                    Some(else_branch) => self.nested(else_branch),
+                    None => true,
+                };
+                // Only the branches that reach the end of the `if` matter after it
+                match (then_falls, else_falls) {
+                    (true, true) => self.unassigned.extend(after_then),
+                    (true, false) => self.unassigned = after_then,
+                    (false, _) => {}
+                }
+                then_falls || else_falls
+            }
+            Statement::While { cond, body, .. } => {
+                self.read(cond);
+                let before = self.unassigned.clone();
+                self.nested(body);
+                // The body may not run at all
+                self.unassigned = before;
+                // There is no `break`, so only the condition can end the loop
+                !matches!(cond.kind, ExprKind::Bool(true))
+            }
+        }
+    }
+
+    /// Check every variable `expr` reads has been assigned on all paths here.
+    fn read(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Number(..) | ExprKind::Bool(_) | ExprKind::StrLiteral(_) => {}
+            ExprKind::Variable(name) => {
+                if let Some(id) = self.lookup(name)
+                    && self.unassigned.contains(&id)
+                    && self.reported.insert(id)
+                {
+                    let (name, line) = &self.decls[id];
+                    self.errors.push(format!(
+                        "Variable {} declared at line {} in {} may be read before it is assigned",
+                        name, line, self.scope
+                    ));
+                }
+            }
+            ExprKind::Unary { expr, .. }
+            | ExprKind::Cast { expr, .. }
+            | ExprKind::Length { array: expr } => self.read(expr),
+            ExprKind::Binary { left, right, .. }
+            | ExprKind::Index {
+                array: left,
+                index: right,
+                ..
+            } => {
+                self.read(left);
+                self.read(right);
+            }
+            ExprKind::Call { args: elems, .. }
+            | ExprKind::ArrayLiteral(elems)
+            | ExprKind::Tuple(elems) => {
+                for e in elems {
+                    self.read(e);
+                }
+            }
+            ExprKind::MethodCall { receiver, args, .. } => {
+                self.read(receiver);
+                for arg in args {
+                    self.read(arg);
+                }
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, line: usize, initialized: bool) {
+        let id = self.decls.len();
+        self.decls.push((name.to_string(), line));
+        self.scopes
+            .last_mut()
+            .expect("declaration outside any block")
+            .insert(name.to_string(), id);
+        if !initialized {
+            self.unassigned.insert(id);
+        }
+    }
+
+    /// The innermost declaration of `name`, if it is a local variable.
+    fn lookup(&self, name: &str) -> Option<usize> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .copied()
+    }
+}
+
+/// The line of a statement that ends control flow.
+fn line(stmt: &Statement) -> usize {
+    match stmt {
+        Statement::Return { line, .. }
+        | Statement::If { line, .. }
+        | Statement::While { line, .. } => *line,
+        _ => unreachable!("only return, if and while end control flow"),
+    }
+}
+
+fn contains_return(stmt: &Statement) -> bool {
+    match stmt {
+        Statement::Return { .. } => true,
+        Statement::If {
+            then_branch,
+            else_branch,
+            ..
+        } => then_branch
+            .iter()
+            .chain(else_branch.iter().flatten())
+            .any(contains_return),
+        Statement::While { body, .. } => body.iter().any(contains_return),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::lint::Level;
+    use crate::parser::Parser;
+
+    /// The flow errors in `src`, and the lints it raises.
+    fn analyse(src: &str) -> (Vec<String>, Vec<String>) {
+        let prog = Parser::new(lex(src).unwrap()).parse_program().unwrap();
+        let mut lints = Lints::default();
+        lints.set_level("warnings", Level::Deny).unwrap();
+        let errors = match check(&prog, &mut lints) {
+            Ok(()) => Vec::new(),
+            Err(CompileError::Flow(msgs)) => msgs,
+            Err(e) => panic!("expected flow errors, got {}", e),
+        };
+        let warnings = match lints.check() {
+            Ok(()) => Vec::new(),
+            Err(CompileError::Lint(msgs)) => msgs,
+            Err(e) => panic!("expected lint errors, got {}", e),
+        };
+        (errors, warnings)
+    }
+
+    fn errors(src: &str) -> Vec<String> {
+        analyse(src).0
+    }
+
+    #[test]
+    fn assigned_in_both_branches_is_assigned() {
+        let src = "let c = 1 < 2; var x; if (c) { x = 1; } else { x = 2; } print(x);";
+        assert_eq!(errors(src), Vec::<String>::new());
+    }
+
+    #[test]
+    fn assigned_in_one_branch_is_not() {
+        let errors = errors("let c = 1 < 2;\nvar x;\nif (c) { x = 1; }\nprint(x);");
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(
+            errors[0].contains("Variable x declared at line 2"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn branches_that_return_do_not_reach_the_merge() {
+        let src = "fn f(c: bool) -> int { var x; if (c) { return 0; } else { x = 1; } return x; }
+                   fn g(c: bool) -> int { var x; if (c) { x = 1; } else { return 0; } return x; }
+                   fn h(c: bool) -> int { var x; if (c) { return 0; } x = 1; return x; }";
+        assert_eq!(errors(src), Vec::<String>::new());
+    }
+
+    #[test]
+    fn loop_bodies_may_not_run() {
+        let src = "var x; var i = 0; while (i < 1) { x = 1; i = i + 1; } print(x);";
+        let errors = errors(src);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("Variable x"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn each_variable_is_reported_once() {
+        let errors = errors("var x; var y; print(x); print(x + y); print(y);");
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].contains("Variable x"), "{}", errors[0]);
+        assert!(errors[1].contains("Variable y"), "{}", errors[1]);
+    }
+
+    #[test]
+    fn functions_must_return_on_every_path() {
+        let errors = errors("fn f(c: bool) -> int { if (c) { return 1; } }");
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].contains("can reach its end"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn code_after_a_return_is_unreachable() {
+        let (_, warnings) = analyse("fn f() -> int {\nreturn 1;\nprint(2);\n}");
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(
+            warnings[0].contains("Unreachable code after line 2"),
+            "{}",
+            warnings[0]
+        );
+    }
+}
@@ -0,0 +1,389 @@
+use crate::ast::*;
+use crate::error::CompileError;
+
+/// Evaluate constant subexpressions, drop branches whose condition is known, and
+/// simplify arithmetic identities such as `x * 1`. Division by a constant zero and
+/// constant arithmetic that overflows are reported as errors.
+pub fn fold(prog: &mut Program) -> Result<(), CompileError> {
+    let mut folder = Folder {
+        scope: String::new(),
+        errors: Vec::new(),
+    };
+    for func in &mut prog.functions {
+        folder.scope = format!("function {}", func.name);
+        folder.block(&mut func.body);
+    }
+    folder.scope = "top-level code".into();
+    folder.block(&mut prog.statements);
+
+    if folder.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(CompileError::Fold(folder.errors))
+    }
+}
+
+struct Folder {
+    scope: String, // where we are, for error messages
+    errors: Vec<String>,
+}
+
+impl Folder {
+    fn block(&mut self, stmts: &mut Vec<Statement>) {
+        let mut folded = Vec::with_capacity(stmts.len());
+        for mut stmt in stmts.drain(..) {
+            self.statement(&mut stmt);
+            match stmt {
+                Statement::If {
+                    cond:
+                        Expr {
+                            kind: ExprKind::Bool(cond),
+                            ty,
+                        },
+                    then_branch,
+                    else_branch,
+                    line,
+                } => match if cond { Some(then_branch) } else { else_branch } {
+                    None => {}
+                    // Splicing the branch in would let its declarations escape
+                    Some(branch) if branch.iter().any(declares) => folded.push(Statement::If {
+                        cond: Expr {
+                            kind: ExprKind::Bool(true),
+                            ty,
+                        },
+                        then_branch: branch,
+                        else_branch: None,
+                        line,
+                    }),
+                    Some(branch) => folded.extend(branch),
+                },
+                Statement::While {
+                    cond:
+                        Expr {
+                            kind: ExprKind::Bool(false),
+                            ..
+                        },
+                    ..
+                } => {}
+                stmt => folded.push(stmt),
+            }
+        }
+        *stmts = folded;
+    }
+
+    fn statement(&mut self, stmt: &mut Statement) {
+        match stmt {
+            Statement::VarDecl { expr, .. } => {
+                if let Some(expr) = expr {
+                    self.expr(expr);
+                }
+            }
+            Statement::LetDecl { expr, .. }
+            | Statement::LetPattern { expr, .. }
+            | Statement::Assign { expr, .. }
+            | Statement::Return { expr, .. }
+            | Statement::Print { expr }
+            | Statement::ExprStmt(expr) => self.expr(expr),
+            Statement::IndexedAssign {
+                array, index, expr, ..
+            } => {
+                self.expr(array);
+                self.expr(index);
+                self.expr(expr);
+            }
+            Statement::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expr(cond);
+                self.block(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.block(else_branch);
+                }
+            }
+            Statement::While { cond, body, .. } => {
+                self.expr(cond);
+                self.block(body);
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Number(n, kind) => {
+                if !kind.range().contains(n) {
+                    self.errors
+                        .push(format!("{} does not fit in {} in {}", n, kind, self.scope));
+                }
+            }
+            ExprKind::Bool(_) | ExprKind::StrLiteral(_) | ExprKind::Variable(_) => {}
+            ExprKind::Unary { expr, .. }
+            | ExprKind::Cast { expr, .. }
+            | ExprKind::Length { array: expr } => self.expr(expr),
+            ExprKind::Binary { left, right, .. }
+            | ExprKind::Index {
+                array: left,
+                index: right,
+                ..
+            } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Call { args: elems, .. }
+            | ExprKind::ArrayLiteral(elems)
+            | ExprKind::Tuple(elems) => {
+                for e in elems {
+                    self.expr(e);
+                }
+            }
+            ExprKind::MethodCall { receiver, args, .. } => {
+                self.expr(receiver);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+        }
+        let folded = match &expr.kind {
+            ExprKind::Unary { op, expr, .. } => self.unary(*op, expr),
+            ExprKind::Binary {
+                op, left, right, ..
+            } => self.binary(*op, left, right),
+            ExprKind::Cast { expr, to } => cast(expr, *to),
+            _ => None,
+        };
+        // The folded form has the same type, so only the kind changes
+        if let Some(folded) = folded {
+            expr.kind = folded;
+        }
+    }
+
+    /// What `op operand` simplifies to, if anything. The operand is already folded.
+    fn unary(&mut self, op: UnOp, operand: &Expr) -> Option<ExprKind> {
+        match (op, &operand.kind) {
+            (UnOp::Pos, ExprKind::Number(n, kind)) => Some(ExprKind::Number(*n, *kind)),
+            (UnOp::Neg, ExprKind::Number(n, kind)) => {
+                self.int(Some(-*n), *kind, || format!("-{}", n))
+            }
+            (UnOp::Not, ExprKind::Bool(b)) => Some(ExprKind::Bool(!b)),
+            _ => None,
+        }
+    }
+
+    /// What `left op right` simplifies to, if anything. Both sides are already folded.
+    fn binary(&mut self, op: BinOp, left: &Expr, right: &Expr) -> Option<ExprKind> {
+        match (op, &left.kind, &right.kind) {
+            (BinOp::Div | BinOp::Rem, _, ExprKind::Number(0, _)) => {
+                self.errors
+                    .push(format!("Division by zero in {}", self.scope));
+                None
+            }
+            (_, ExprKind::Number(l, kind), ExprKind::Number(r, _)) => {
+                let (l, r, kind) = (*l, *r, *kind);
+                let describe = || format!("{} {} {}", l, symbol(op), r);
+                match op {
+                    BinOp::Add => self.int(l.checked_add(r), kind, describe),
+                    BinOp::Sub => self.int(l.checked_sub(r), kind, describe),
+                    BinOp::Mul => self.int(l.checked_mul(r), kind, describe),
+                    BinOp::Div => self.int(l.checked_div(r), kind, describe),
+                    BinOp::Rem => self.int(l.checked_rem(r), kind, describe),
+                    BinOp::Lt => Some(ExprKind::Bool(l < r)),
+                    BinOp::Le => Some(ExprKind::Bool(l <= r)),
+                    BinOp::Gt => Some(ExprKind::Bool(l > r)),
+                    BinOp::Ge => Some(ExprKind::Bool(l >= r)),
+                    BinOp::Eq => Some(ExprKind::Bool(l == r)),
+                    BinOp::Ne => Some(ExprKind::Bool(l != r)),
+                    BinOp::And | BinOp::Or => None,
+                }
+            }
+            (BinOp::Add, ExprKind::StrLiteral(l), ExprKind::StrLiteral(r)) => {
+                Some(ExprKind::StrLiteral(format!("{}{}", l, r)))
+            }
+            (BinOp::Eq, ExprKind::Bool(l), ExprKind::Bool(r)) => Some(ExprKind::Bool(l == r)),
+            (BinOp::Ne, ExprKind::Bool(l), ExprKind::Bool(r)) => Some(ExprKind::Bool(l != r)),
+            // A constant left side decides whether the right is evaluated at all
+            (BinOp::And, ExprKind::Bool(false), _) => Some(ExprKind::Bool(false)),
+            (BinOp::Or, ExprKind::Bool(true), _) => Some(ExprKind::Bool(true)),
+            (BinOp::And, ExprKind::Bool(true), e)
+            | (BinOp::Or, ExprKind::Bool(false), e)
+            | (BinOp::And, e, ExprKind::Bool(true))
+            | (BinOp::Or, e, ExprKind::Bool(false)) => Some(e.clone()),
+            (BinOp::Add, e, ExprKind::Number(0, _))
+            | (BinOp::Add, ExprKind::Number(0, _), e)
+            | (BinOp::Sub, e, ExprKind::Number(0, _))
+            | (BinOp::Mul, e, ExprKind::Number(1, _))
+            | (BinOp::Mul, ExprKind::Number(1, _), e)
+            | (BinOp::Div, e, ExprKind::Number(1, _)) => Some(e.clone()),
+            _ => None,
+        }
+    }
+
+    /// `value` as a constant of type `kind`, or an error if it does not fit.
+    fn int(
+        &mut self,
+        value: Option<i128>,
+        kind: IntKind,
+        describe: impl Fn() -> String,
+    ) -> Option<ExprKind> {
+        match value {
+            Some(value) if kind.range().contains(&value) => Some(ExprKind::Number(value, kind)),
+            _ => {
+                self.errors.push(format!(
+                    "{} overflows {} in {}",
+                    describe(),
+                    kind,
+                    self.scope
+                ));
+                None
+            }
+        }
+    }
+}
+
+/// What `operand as to` is, if the operand is a constant.
+fn cast(operand: &Expr, to: IntKind) -> Option<ExprKind> {
+    match &operand.kind {
+        ExprKind::Number(n, _) => Some(ExprKind::Number(to.wrap(*n), to)),
+        ExprKind::Bool(b) => Some(ExprKind::Number(*b as i128, to)),
+        _ => None,
+    }
+}
+
+/// Whether `stmt` declares a name in the block it appears in.
+fn declares(stmt: &Statement) -> bool {
+    matches!(
+        stmt,
+        Statement::VarDecl { .. } | Statement::LetDecl { .. } | Statement::LetPattern { .. }
+    )
+}
+
+fn symbol(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::Rem => "%",
+        BinOp::Lt => "<",
+        BinOp::Le => "<=",
+        BinOp::Gt => ">",
+        BinOp::Ge => ">=",
+        BinOp::Eq => "==",
+        BinOp::Ne => "!=",
+        BinOp::And => "&&",
+        BinOp::Or => "||",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::parser::Parser;
+    use crate::typeck;
+
+    /// `src` type checked, for the literal types, and folded.
+    fn folded(src: &str) -> Result<Program, CompileError> {
+        let mut prog = Parser::new(lex(src)?).parse_program()?;
+        typeck::check(&mut prog)?;
+        fold(&mut prog)?;
+        Ok(prog)
+    }
+
+    fn fold_errors(src: &str) -> Vec<String> {
+        match folded(src) {
+            Err(CompileError::Fold(msgs)) => msgs,
+            Err(e) => panic!("expected constant errors, got {}", e),
+            Ok(_) => panic!("expected constant errors"),
+        }
+    }
+
+    /// The expressions printed by the top-level code of `src`, folded.
+    fn printed(src: &str) -> Vec<ExprKind> {
+        folded(src)
+            .unwrap()
+            .statements
+            .into_iter()
+            .filter_map(|stmt| match stmt {
+                Statement::Print { expr } => Some(expr.kind),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn constant_arithmetic_is_evaluated() {
+        let printed = printed("print(2 + 3 * 4); print(7 % 4 == 3); print(300 as u8);");
+        assert!(matches!(printed[0], ExprKind::Number(14, IntKind::I64)));
+        assert!(matches!(printed[1], ExprKind::Bool(true)));
+        assert!(matches!(printed[2], ExprKind::Number(44, IntKind::U8)));
+    }
+
+    #[test]
+    fn overflow_is_reported() {
+        let errors = fold_errors("print(9223372036854775807 + 1); let b: u8 = 200 + 100;");
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(errors[0].contains("overflows int"), "{}", errors[0]);
+        assert!(
+            errors[1].contains("200 + 100 overflows u8"),
+            "{}",
+            errors[1]
+        );
+    }
+
+    #[test]
+    fn division_by_zero_is_reported() {
+        let errors = fold_errors("fn f(x) { return x / 0 + x % 0; }");
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(
+            errors[0].contains("Division by zero in function f"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn identities_are_simplified() {
+        let printed =
+            printed("let x = 5 < 6; let n = 7; print(n * 1); print(0 + n); print(x && true);");
+        assert_eq!(printed.len(), 3);
+        for kind in printed {
+            assert!(matches!(kind, ExprKind::Variable(_)), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn constant_branches_are_spliced_in_or_dropped() {
+        let prog = folded(
+            "if (true) { print(1); } else { print(2); }
+             if (false) { print(3); }
+             while (false) { print(4); }",
+        )
+        .unwrap();
+        assert_eq!(prog.statements.len(), 1);
+        let Statement::Print { expr } = &prog.statements[0] else {
+            panic!("expected the then branch");
+        };
+        assert!(matches!(expr.kind, ExprKind::Number(1, _)));
+    }
+
+    #[test]
+    fn constant_branches_that_declare_names_stay_blocks() {
+        let prog =
+            folded("let x = 1; if (true) { let x = 2; print(x); } else { print(3); } print(x);")
+                .unwrap();
+        let Statement::If {
+            cond,
+            then_branch,
+            else_branch,
+            ..
+        } = &prog.statements[1]
+        else {
+            panic!("expected the if to stay");
+        };
+        assert!(matches!(cond.kind, ExprKind::Bool(true)));
+        assert_eq!(then_branch.len(), 2);
+        assert!(else_branch.is_none());
+    }
+}
@@ -13,9 +13,10 @@ pub enum Token {
     While,
     Return,
     Print,
+    As,
     // Identifiers and literals
     Ident(String),
-    Number(i64),
+    Number(u64),
     StrLiteral(String),
     BoolLiteral(bool),
     // Operators
@@ -28,9 +29,12 @@ pub enum Token {
     Le,
     Gt,
     Ge,
-    EqEq, // ==
-    Ne,   // !=
-    Eq,   // =
+    EqEq,   // ==
+    Ne,     // !=
+    Eq,     // =
+    AndAnd, // &&
+    OrOr,   // ||
+    Bang,   // !
     // Delimiters
     LParen,
     RParen,
@@ -39,9 +43,11 @@ pub enum Token {
     LBracket,
     RBracket,
     Comma,
+    Colon,
     Semicolon,
     // Special
     Dot,
+    Arrow, // ->
     EOF,
 }
 
@@ -52,14 +58,21 @@ fn is_ident_continue(c: char) -> bool {
     c.is_ascii_alphanumeric() || c == '_'
 }
 
-pub fn lex(input: &str) -> Result<Vec<Token>, CompileError> {
+/// Split `input` into tokens, each paired with the line it starts on.
+pub fn lex(input: &str) -> Result<Vec<(Token, usize)>, CompileError> {
     let mut tokens = Vec::new();
+    let mut lines = Vec::new();
+    let mut line = 1;
     let mut chars = input.chars().peekable();
 
     while let Some(&ch) = chars.peek() {
+        let start_line = line;
         match ch {
             // Skip whitespace
             c if c.is_whitespace() => {
+                if c == '\n' {
+                    line += 1;
+                }
                 chars.next();
             }
             // Two‐char operators
@@ -96,7 +109,25 @@ pub fn lex(input: &str) -> Result<Vec<Token>, CompileError> {
                     chars.next();
                     tokens.push(Token::Ne);
                 } else {
-                    return Err(CompileError::Lex("Unexpected '!'".into()));
+                    tokens.push(Token::Bang);
+                }
+            }
+            '&' => {
+                chars.next();
+                if chars.peek() == Some(&'&') {
+                    chars.next();
+                    tokens.push(Token::AndAnd);
+                } else {
+                    return Err(CompileError::Lex("Unexpected '&'".into()));
+                }
+            }
+            '|' => {
+                chars.next();
+                if chars.peek() == Some(&'|') {
+                    chars.next();
+                    tokens.push(Token::OrOr);
+                } else {
+                    return Err(CompileError::Lex("Unexpected '|'".into()));
                 }
             }
             // Single‐char operators/delimiters
@@ -106,7 +137,12 @@ pub fn lex(input: &str) -> Result<Vec<Token>, CompileError> {
             }
             '-' => {
                 chars.next();
-                tokens.push(Token::Minus);
+                if chars.peek() == Some(&'>') {
+                    chars.next();
+                    tokens.push(Token::Arrow);
+                } else {
+                    tokens.push(Token::Minus);
+                }
             }
             '*' => {
                 chars.next();
@@ -140,6 +176,10 @@ pub fn lex(input: &str) -> Result<Vec<Token>, CompileError> {
                 chars.next();
                 tokens.push(Token::Comma);
             }
+            ':' => {
+                chars.next();
+                tokens.push(Token::Colon);
+            }
             ';' => {
                 chars.next();
                 tokens.push(Token::Semicolon);
@@ -165,6 +205,9 @@ pub fn lex(input: &str) -> Result<Vec<Token>, CompileError> {
                         chars.next();
                         break;
                     }
+                    if c2 == '\n' {
+                        line += 1;
+                    }
                     s.push(c2);
                     chars.next();
                 }
@@ -172,15 +215,21 @@ pub fn lex(input: &str) -> Result<Vec<Token>, CompileError> {
             }
             // Number literal
             c if c.is_ascii_digit() => {
-                let mut val = 0i64;
+                let mut digits = String::new();
                 while let Some(&d) = chars.peek() {
                     if d.is_ascii_digit() {
-                        val = val * 10 + (d as u8 - b'0') as i64;
+                        digits.push(d);
                         chars.next();
                     } else {
                         break;
                     }
                 }
+                let val = digits.parse().map_err(|_| {
+                    CompileError::Lex(format!(
+                        "Integer literal {} at line {} does not fit in u64, the widest integer type",
+                        digits, line
+                    ))
+                })?;
                 tokens.push(Token::Number(val));
             }
             // Identifier or keyword or boolean
@@ -205,6 +254,7 @@ pub fn lex(input: &str) -> Result<Vec<Token>, CompileError> {
                     "while" => Token::While,
                     "return" => Token::Return,
                     "print" => Token::Print,
+                    "as" => Token::As,
                     "true" => Token::BoolLiteral(true),
                     "false" => Token::BoolLiteral(false),
                     _ => Token::Ident(ident),
@@ -218,8 +268,11 @@ pub fn lex(input: &str) -> Result<Vec<Token>, CompileError> {
                 )));
             }
         }
+        // Whatever this step produced starts on the line it began on
+        lines.resize(tokens.len(), start_line);
     }
 
     tokens.push(Token::EOF);
-    Ok(tokens)
+    lines.push(line);
+    Ok(tokens.into_iter().zip(lines).collect())
 }
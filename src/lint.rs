@@ -0,0 +1,96 @@
+use crate::error::CompileError;
+use std::collections::HashMap;
+
+/// A kind of warning that can be allowed, reported, or turned into an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnusedVariables,
+    UnusedParameters,
+    UnusedFunctions,
+    UnusedResults,
+    NeverReassigned,
+    UnreachableCode,
+}
+
+const LINTS: &[Lint] = &[
+    Lint::UnusedVariables,
+    Lint::UnusedParameters,
+    Lint::UnusedFunctions,
+    Lint::UnusedResults,
+    Lint::NeverReassigned,
+    Lint::UnreachableCode,
+];
+
+impl Lint {
+    /// The name used on the command line and in messages.
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UnusedVariables => "unused_variables",
+            Lint::UnusedParameters => "unused_parameters",
+            Lint::UnusedFunctions => "unused_functions",
+            Lint::UnusedResults => "unused_results",
+            Lint::NeverReassigned => "never_reassigned",
+            Lint::UnreachableCode => "unreachable_code",
+        }
+    }
+}
+
+/// What to do when a lint fires.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// The warnings channel: every pass reports through it, and it decides
+/// from the configured levels whether each report is dropped, printed, or fatal.
+pub struct Lints {
+    levels: HashMap<Lint, Level>,
+    denied: Vec<String>,
+}
+
+/// All lints at `Warn`.
+impl Default for Lints {
+    fn default() -> Self {
+        Lints {
+            levels: LINTS.iter().map(|&lint| (lint, Level::Warn)).collect(),
+            denied: Vec::new(),
+        }
+    }
+}
+
+impl Lints {
+    /// Set the level of the lint called `name`, or of all of them for `warnings`.
+    pub fn set_level(&mut self, name: &str, level: Level) -> Result<(), CompileError> {
+        if name == "warnings" {
+            for lint in LINTS {
+                self.levels.insert(*lint, level);
+            }
+            return Ok(());
+        }
+        let lint = LINTS
+            .iter()
+            .find(|lint| lint.name() == name)
+            .ok_or_else(|| CompileError::Io(format!("Unknown lint {}", name)))?;
+        self.levels.insert(*lint, level);
+        Ok(())
+    }
+
+    pub fn report(&mut self, lint: Lint, msg: String) {
+        match self.levels[&lint] {
+            Level::Allow => {}
+            Level::Warn => eprintln!("Warning: {} [{}]", msg, lint.name()),
+            Level::Deny => self.denied.push(format!("{} [{}]", msg, lint.name())),
+        }
+    }
+
+    /// Fail if anything reported so far was at `Deny`.
+    pub fn check(&mut self) -> Result<(), CompileError> {
+        if self.denied.is_empty() {
+            Ok(())
+        } else {
+            Err(CompileError::Lint(std::mem::take(&mut self.denied)))
+        }
+    }
+}
@@ -1,15 +1,24 @@
 use crate::error::CompileError;
 use std::env;
 use std::fs;
+use std::path::{Path, PathBuf};
 
 mod ast;
+mod backend;
 mod codegen;
 mod error;
+mod flow;
+mod fold;
 mod lexer;
+mod lint;
 mod parser;
+mod resolve;
+mod typeck;
 
 use ast::Program;
+use backend::{Emit, OptLevel};
 use codegen::CodeGen;
+use lint::{Level, Lints};
 
 fn main() {
     if let Err(e) = run() {
@@ -18,31 +27,250 @@ fn main() {
     }
 }
 
+/// What the command line asked for.
+struct Options {
+    path: String,
+    lints: Lints,
+    bounds_checks: bool,
+    /// On by default only at `-O0`.
+    overflow_checks: bool,
+    opt_level: OptLevel,
+    /// A custom `--passes=` pipeline, run instead of the one for `opt_level`.
+    passes: Option<String>,
+    /// Write `program.ll` even when code generation fails, to attach to bug reports.
+    dump_ir_on_error: bool,
+    /// Where `build` writes its output; without `build` the program is run in the JIT.
+    output: Option<PathBuf>,
+    emit: Emit,
+    /// The triple `build` compiles for, instead of the host.
+    target: Option<String>,
+    /// The CPU `build` compiles for, or `native` for the host's.
+    target_cpu: Option<String>,
+    /// LLVM target features, like `+avx2,-sse4a`, instead of the CPU's defaults.
+    target_features: Option<String>,
+}
+
+/// Parse `toy_compiler [build [-o output] [--emit=exe|obj|asm] [--target triple]
+/// [--target-cpu cpu] [--target-features features]] [-A|-W|-D lint]... [-O0|-O1|-O2|-O3|-Os] [--passes=pipeline] [--no-bounds-checks]
+/// [--overflow-checks|--no-overflow-checks] [--dump-ir-on-error] file.toy`.
+fn parse_args() -> Result<Options, CompileError> {
+    let mut path = None;
+    let mut lints = Lints::default();
+    let mut bounds_checks = true;
+    let mut overflow_checks = None;
+    let mut opt_level = OptLevel::default();
+    let mut passes = None;
+    let mut dump_ir_on_error = false;
+    let mut output = None;
+    let mut emit = Emit::default();
+    let mut target = None;
+    let mut target_cpu = None;
+    let mut target_features = None;
+    let mut args = env::args().skip(1).peekable();
+    let build = args.next_if(|arg| arg == "build").is_some();
+    while let Some(arg) = args.next() {
+        if let Some(level) = OptLevel::from_flag(&arg) {
+            opt_level = level;
+            continue;
+        }
+        if let Some(pipeline) = arg.strip_prefix("--passes=") {
+            passes = Some(pipeline.to_string());
+            continue;
+        }
+        if let Some(name) = arg.strip_prefix("--emit=") {
+            if !build {
+                return Err(CompileError::Io("--emit only applies to build".into()));
+            }
+            emit = Emit::from_name(name).ok_or_else(|| {
+                CompileError::Io(format!("Unknown --emit {}; expected exe, obj or asm", name))
+            })?;
+            continue;
+        }
+        let level = match arg.as_str() {
+            "--no-bounds-checks" => {
+                bounds_checks = false;
+                continue;
+            }
+            "--overflow-checks" => {
+                overflow_checks = Some(true);
+                continue;
+            }
+            "--no-overflow-checks" => {
+                overflow_checks = Some(false);
+                continue;
+            }
+            "--dump-ir-on-error" => {
+                dump_ir_on_error = true;
+                continue;
+            }
+            "-o" if build => {
+                let file = args
+                    .next()
+                    .ok_or_else(|| CompileError::Io("-o needs a file name".into()))?;
+                output = Some(PathBuf::from(file));
+                continue;
+            }
+            "--target" if build => {
+                let triple = args
+                    .next()
+                    .ok_or_else(|| CompileError::Io("--target needs a triple".into()))?;
+                target = Some(triple);
+                continue;
+            }
+            "--target-cpu" if build => {
+                let cpu = args
+                    .next()
+                    .ok_or_else(|| CompileError::Io("--target-cpu needs a CPU name".into()))?;
+                target_cpu = Some(cpu);
+                continue;
+            }
+            "--target-features" if build => {
+                let features = args.next().ok_or_else(|| {
+                    CompileError::Io("--target-features needs a feature list".into())
+                })?;
+                target_features = Some(features);
+                continue;
+            }
+            "-o" | "--target" | "--target-cpu" | "--target-features" => {
+                return Err(CompileError::Io(format!("{} only applies to build", arg)));
+            }
+            "-A" => Level::Allow,
+            "-W" => Level::Warn,
+            "-D" => Level::Deny,
+            _ if path.is_none() => {
+                path = Some(arg);
+                continue;
+            }
+            _ => return Err(CompileError::Io(format!("Unexpected argument {}", arg))),
+        };
+        let name = args
+            .next()
+            .ok_or_else(|| CompileError::Io(format!("{} needs a lint name", arg)))?;
+        lints.set_level(&name, level)?;
+    }
+    let path: String = path.ok_or_else(|| CompileError::Io("No input file specified".into()))?;
+    if target.is_some() && emit == Emit::Exe {
+        return Err(CompileError::Io(
+            "Executables can only be linked for the host; add --emit=obj or --emit=asm".into(),
+        ));
+    }
+    if target.is_some() && target_cpu.as_deref() == Some("native") {
+        return Err(CompileError::Io(
+            "--target-cpu native only applies to the host; name a CPU for the target".into(),
+        ));
+    }
+    if build && output.is_none() {
+        // `build algo.toy` makes `algo`, `algo.o` or `algo.s`
+        output = Some(Path::new(&path).with_extension(emit.extension()));
+    }
+    if output.as_deref() == Some(Path::new(&path)) {
+        return Err(CompileError::Io(format!(
+            "The output would overwrite {}; name it with -o",
+            path
+        )));
+    }
+    Ok(Options {
+        path,
+        lints,
+        bounds_checks,
+        overflow_checks: overflow_checks.unwrap_or(opt_level == OptLevel::O0),
+        opt_level,
+        passes,
+        dump_ir_on_error,
+        output,
+        emit,
+        target,
+        target_cpu,
+        target_features,
+    })
+}
+
 fn run() -> Result<(), CompileError> {
-    let path = env::args()
-        .nth(1)
-        .ok_or_else(|| CompileError::Io("No input file specified".into()))?;
-    let src = fs::read_to_string(path).map_err(|e| CompileError::Io(e.to_string()))?;
+    let Options {
+        path,
+        mut lints,
+        bounds_checks,
+        overflow_checks,
+        opt_level,
+        passes,
+        dump_ir_on_error,
+        output,
+        emit,
+        target,
+        target_cpu,
+        target_features,
+    } = parse_args()?;
+    let src = fs::read_to_string(&path).map_err(|e| CompileError::Io(e.to_string()))?;
 
     // lex & parse
     let tokens = lexer::lex(&src)?;
     let mut parser = parser::Parser::new(tokens);
-    let prog: Program = parser.parse_program()?;
+    let mut prog: Program = parser.parse_program()?;
+
+    // name resolution, control flow & type checking
+    resolve::resolve(&prog, &mut lints)?;
+    flow::check(&prog, &mut lints)?;
+    lints.check()?;
+    typeck::check(&mut prog)?;
+
+    // constant folding
+    fold::fold(&mut prog)?;
 
     // codegen
     let ctx = inkwell::context::Context::create();
     let module = ctx.create_module("toy");
-    let mut cg = CodeGen::new(&ctx, module);
-    cg.compile_program(&prog)?;
+    let mut cg = CodeGen::new(&ctx, module, &path, bounds_checks, overflow_checks);
+    if let Err(e) = cg.compile_program(&prog).and_then(|()| cg.verify()) {
+        if dump_ir_on_error {
+            write_ir(&cg)?;
+            return Err(match e {
+                CompileError::Codegen(msg) => CompileError::Codegen(format!(
+                    "{}\nThe IR generated so far was written to program.ll",
+                    msg
+                )),
+                other => other,
+            });
+        }
+        return Err(e);
+    }
 
-    // let ir = cg.module.print_to_string().to_string();
-    std::fs::write("program.ll", cg.module.print_to_string().to_string())
-        .map_err(|e| CompileError::Io(format!("Failed to write IR file: {}", e)))?;
+    // optimization
+    let machine = backend::target_machine(
+        &cg.module,
+        target.as_deref(),
+        target_cpu.as_deref(),
+        target_features.as_deref(),
+        opt_level,
+    )?;
+    match &passes {
+        Some(pipeline) => backend::optimize(&cg.module, &machine, pipeline)?,
+        None if opt_level != OptLevel::O0 => {
+            backend::optimize(&cg.module, &machine, opt_level.pipeline())?
+        }
+        None => {}
+    }
+    write_ir(&cg)?;
+
+    // native output
+    if let Some(output) = output {
+        if emit != Emit::Exe {
+            return backend::write_file(&cg.module, &machine, emit.file_type(), &output);
+        }
+        // Not next to the output, where it could be the output itself or clobber a
+        // file of the same name
+        let object = env::temp_dir().join(format!("toy-{}.o", std::process::id()));
+        backend::write_file(&cg.module, &machine, emit.file_type(), &object)?;
+        let linked = backend::link(&object, &output);
+        fs::remove_file(&object).map_err(|e| {
+            CompileError::Io(format!("Failed to remove {}: {}", object.display(), e))
+        })?;
+        return linked;
+    }
 
     // JIT & run
     let ee = cg
         .module
-        .create_jit_execution_engine(inkwell::OptimizationLevel::None)
+        .create_jit_execution_engine(opt_level.codegen_level())
         .map_err(|e| CompileError::Codegen(format!("{:?}", e)))?;
     unsafe {
         let main_fn = ee
@@ -52,3 +280,8 @@ fn run() -> Result<(), CompileError> {
     }
     Ok(())
 }
+
+fn write_ir(cg: &CodeGen) -> Result<(), CompileError> {
+    std::fs::write("program.ll", cg.module.print_to_string().to_string())
+        .map_err(|e| CompileError::Io(format!("Failed to write IR file: {}", e)))
+}
@@ -4,17 +4,28 @@ use crate::lexer::Token;
 
 pub struct Parser {
     tokens: Vec<Token>,
+    lines: Vec<usize>, // source line of each token
     pos: usize,
+    type_params: Vec<String>, // of the function being parsed, for its annotations
 }
 
 impl Parser {
-    pub fn new(tokens: Vec<Token>) -> Self {
-        Parser { tokens, pos: 0 }
+    pub fn new(tokens: Vec<(Token, usize)>) -> Self {
+        let (tokens, lines) = tokens.into_iter().unzip();
+        Parser {
+            tokens,
+            lines,
+            pos: 0,
+            type_params: Vec::new(),
+        }
     }
 
     fn peek(&self) -> &Token {
         &self.tokens[self.pos]
     }
+    fn line(&self) -> usize {
+        self.lines[self.pos]
+    }
     fn eat(&mut self) {
         if self.pos < self.tokens.len() {
             self.pos += 1
@@ -56,24 +67,46 @@ impl Parser {
         })
     }
 
-    /// Parse `fn name(arg1, arg2, …) { … }`
+    /// Parse `fn name<T, …>(arg1: type, arg2, …) -> type { … }`
     fn parse_function(&mut self) -> Result<Function, CompileError> {
+        let line = self.line();
         self.expect(Token::Fn)?;
         let name = match self.peek() {
             Token::Ident(n) => n.clone(),
             _ => return Err(CompileError::Parse("Expected function name".into())),
         };
         self.eat();
+        let mut type_params = Vec::new();
+        if *self.peek() == Token::Lt {
+            self.eat();
+            loop {
+                if let Token::Ident(n) = self.peek() {
+                    type_params.push(n.clone());
+                    self.eat();
+                } else {
+                    return Err(CompileError::Parse("Expected type parameter name".into()));
+                }
+                if *self.peek() == Token::Comma {
+                    self.eat();
+                    continue;
+                }
+                break;
+            }
+            self.expect(Token::Gt)?;
+        }
+        self.type_params = type_params;
         self.expect(Token::LParen)?;
         let mut params = Vec::new();
         if *self.peek() != Token::RParen {
             loop {
-                if let Token::Ident(n) = self.peek() {
-                    params.push(n.clone());
+                let pattern = self.parse_pattern()?;
+                let ty = if *self.peek() == Token::Colon {
                     self.eat();
+                    Some(self.parse_type()?)
                 } else {
-                    return Err(CompileError::Parse("Expected parameter name".into()));
-                }
+                    None
+                };
+                params.push(Param { pattern, ty });
                 if *self.peek() == Token::Comma {
                     self.eat();
                     continue;
@@ -82,8 +115,129 @@ impl Parser {
             }
         }
         self.expect(Token::RParen)?;
+        let return_type = if *self.peek() == Token::Arrow {
+            self.eat();
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
         let body = self.parse_block()?;
-        Ok(Function { name, params, body })
+        Ok(Function {
+            name,
+            line,
+            type_params: std::mem::take(&mut self.type_params),
+            params,
+            return_type,
+            body,
+        })
+    }
+
+    /// Parse a binding pattern: `name`, `_`, `(p, …)` or `[p, …]`.
+    fn parse_pattern(&mut self) -> Result<Pattern, CompileError> {
+        match self.peek() {
+            Token::Ident(n) => {
+                let name = n.clone();
+                self.eat();
+                if *self.peek() == Token::LBrace {
+                    return Err(CompileError::Parse(format!(
+                        "Cannot destructure {} {{ … }}: the language has no struct types",
+                        name
+                    )));
+                }
+                if name == "_" {
+                    Ok(Pattern::Wildcard)
+                } else {
+                    Ok(Pattern::Ident(name))
+                }
+            }
+            Token::LParen => {
+                self.eat();
+                let elems = self.parse_pattern_list(Token::RParen)?;
+                Ok(Pattern::Tuple(elems))
+            }
+            Token::LBracket => {
+                self.eat();
+                let elems = self.parse_pattern_list(Token::RBracket)?;
+                Ok(Pattern::Array(elems))
+            }
+            other => Err(CompileError::Parse(format!(
+                "Expected a pattern, found {:?}",
+                other
+            ))),
+        }
+    }
+
+    /// Parse comma-separated patterns up to and including `close`.
+    fn parse_pattern_list(&mut self, close: Token) -> Result<Vec<Pattern>, CompileError> {
+        let mut elems = Vec::new();
+        if *self.peek() != close {
+            loop {
+                elems.push(self.parse_pattern()?);
+                if *self.peek() == Token::Comma {
+                    self.eat();
+                    continue;
+                }
+                break;
+            }
+        }
+        self.expect(close)?;
+        Ok(elems)
+    }
+
+    /// Parse an optional `: type` after a variable name.
+    fn parse_annotation(&mut self) -> Result<Option<Type>, CompileError> {
+        if *self.peek() == Token::Colon {
+            self.eat();
+            Ok(Some(self.parse_type()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Parse a type annotation: `int`, `bool`, `string`, `[T]`, `list<T>`, `(T, U)` or a type
+    /// parameter of the enclosing function.
+    fn parse_type(&mut self) -> Result<Type, CompileError> {
+        match self.peek() {
+            Token::Ident(n) => {
+                let name = n.clone();
+                self.eat();
+                if let Some(kind) = IntKind::from_name(&name) {
+                    return Ok(Type::Int(kind));
+                }
+                match name.as_str() {
+                    "bool" => Ok(Type::Bool),
+                    "string" => Ok(Type::Str),
+                    "list" => {
+                        self.expect(Token::Lt)?;
+                        let elem = self.parse_type()?;
+                        self.expect(Token::Gt)?;
+                        Ok(Type::List(Box::new(elem)))
+                    }
+                    _ if self.type_params.contains(&name) => Ok(Type::Param(name)),
+                    _ => Err(CompileError::Parse(format!("Unknown type {}", name))),
+                }
+            }
+            Token::LBracket => {
+                self.eat();
+                let elem = self.parse_type()?;
+                self.expect(Token::RBracket)?;
+                Ok(Type::Array(Box::new(elem)))
+            }
+            Token::LParen => {
+                self.eat();
+                let mut elems = vec![self.parse_type()?];
+                while *self.peek() == Token::Comma {
+                    self.eat();
+                    elems.push(self.parse_type()?);
+                }
+                self.expect(Token::RParen)?;
+                Ok(Type::Tuple(elems))
+            }
+            other => Err(CompileError::Parse(format!(
+                "Expected a type, found {:?}",
+                other
+            ))),
+        }
     }
 
     /// Parse a `{ stmt; stmt; … }` block
@@ -103,6 +257,7 @@ impl Parser {
 
     /// Parse any single statement (var, assign, indexed assign, if, while, return, print, expr‐stmt).
     fn parse_statement(&mut self) -> Result<Statement, CompileError> {
+        let line = self.line();
         match self.peek() {
             Token::Var => {
                 self.eat();
@@ -112,21 +267,42 @@ impl Parser {
                     return Err(CompileError::Parse("Expected var name".into()));
                 };
                 self.eat();
-                self.expect(Token::Eq)?;
-                let expr = self.parse_expr()?;
-                Ok(Statement::VarDecl { name, expr })
+                let ty = self.parse_annotation()?;
+                let expr = if *self.peek() == Token::Eq {
+                    self.eat();
+                    Some(self.parse_expr()?)
+                } else {
+                    None
+                };
+                Ok(Statement::VarDecl {
+                    name,
+                    ty,
+                    expr,
+                    line,
+                })
             }
             Token::Let => {
                 self.eat();
-                let name = if let Token::Ident(n) = self.peek() {
-                    n.clone()
-                } else {
-                    return Err(CompileError::Parse("Expected let name".into()));
+                let pattern = self.parse_pattern()?;
+                let ty = match pattern {
+                    Pattern::Ident(_) => self.parse_annotation()?,
+                    _ => None,
                 };
-                self.eat();
                 self.expect(Token::Eq)?;
                 let expr = self.parse_expr()?;
-                Ok(Statement::LetDecl { name, expr })
+                match pattern {
+                    Pattern::Ident(name) => Ok(Statement::LetDecl {
+                        name,
+                        ty,
+                        expr,
+                        line,
+                    }),
+                    pattern => Ok(Statement::LetPattern {
+                        pattern,
+                        expr,
+                        line,
+                    }),
+                }
             }
             Token::If => {
                 self.eat();
@@ -144,6 +320,7 @@ impl Parser {
                     cond,
                     then_branch,
                     else_branch,
+                    line,
                 })
             }
             Token::While => {
@@ -152,12 +329,12 @@ impl Parser {
                 let cond = self.parse_expr()?;
                 self.expect(Token::RParen)?;
                 let body = self.parse_block()?;
-                Ok(Statement::While { cond, body })
+                Ok(Statement::While { cond, body, line })
             }
             Token::Return => {
                 self.eat();
                 let expr = self.parse_expr()?;
-                Ok(Statement::Return { expr })
+                Ok(Statement::Return { expr, line })
             }
             Token::Print => {
                 self.eat();
@@ -170,12 +347,17 @@ impl Parser {
                 if *self.peek() == Token::Eq {
                     self.eat();
                     let value = self.parse_expr()?;
-                    match expr {
-                        Expr::Variable(name) => Ok(Statement::Assign { name, expr: value }),
-                        Expr::Index { array, index } => Ok(Statement::IndexedAssign {
+                    match expr.kind {
+                        ExprKind::Variable(name) => Ok(Statement::Assign {
+                            name,
+                            expr: value,
+                            line,
+                        }),
+                        ExprKind::Index { array, index, .. } => Ok(Statement::IndexedAssign {
                             array,
                             index,
                             expr: Box::new(value),
+                            line,
                         }),
                         _ => Err(CompileError::Parse(
                             "Left-hand side of assignment must be a variable or array index".into(),
@@ -193,9 +375,41 @@ impl Parser {
     }
 
     /// Parse expressions with correct precedence:
-    /// equality -> comparison -> addition -> term -> factor -> primary
+    /// or -> and -> equality -> comparison -> addition -> term -> cast -> factor -> primary
     fn parse_expr(&mut self) -> Result<Expr, CompileError> {
-        self.parse_equality()
+        self.parse_or()
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, CompileError> {
+        let mut lhs = self.parse_and()?;
+        while *self.peek() == Token::OrOr {
+            let line = self.line();
+            self.eat();
+            let rhs = self.parse_and()?;
+            lhs = Expr::new(ExprKind::Binary {
+                op: BinOp::Or,
+                left: Box::new(lhs),
+                right: Box::new(rhs),
+                line,
+            });
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, CompileError> {
+        let mut lhs = self.parse_equality()?;
+        while *self.peek() == Token::AndAnd {
+            let line = self.line();
+            self.eat();
+            let rhs = self.parse_equality()?;
+            lhs = Expr::new(ExprKind::Binary {
+                op: BinOp::And,
+                left: Box::new(lhs),
+                right: Box::new(rhs),
+                line,
+            });
+        }
+        Ok(lhs)
     }
 
     fn parse_equality(&mut self) -> Result<Expr, CompileError> {
@@ -206,13 +420,15 @@ impl Parser {
                 Token::Ne => BinOp::Ne,
                 _ => unreachable!(),
             };
+            let line = self.line();
             self.eat();
             let rhs = self.parse_comparison()?;
-            lhs = Expr::Binary {
+            lhs = Expr::new(ExprKind::Binary {
                 op,
                 left: Box::new(lhs),
                 right: Box::new(rhs),
-            };
+                line,
+            });
         }
         Ok(lhs)
     }
@@ -227,13 +443,15 @@ impl Parser {
                 Token::Ge => BinOp::Ge,
                 _ => unreachable!(),
             };
+            let line = self.line();
             self.eat();
             let rhs = self.parse_addition()?;
-            lhs = Expr::Binary {
+            lhs = Expr::new(ExprKind::Binary {
                 op,
                 left: Box::new(lhs),
                 right: Box::new(rhs),
-            };
+                line,
+            });
         }
         Ok(lhs)
     }
@@ -246,19 +464,21 @@ impl Parser {
             } else {
                 BinOp::Sub
             };
+            let line = self.line();
             self.eat();
             let rhs = self.parse_term()?;
-            lhs = Expr::Binary {
+            lhs = Expr::new(ExprKind::Binary {
                 op,
                 left: Box::new(lhs),
                 right: Box::new(rhs),
-            };
+                line,
+            });
         }
         Ok(lhs)
     }
 
     fn parse_term(&mut self) -> Result<Expr, CompileError> {
-        let mut lhs = self.parse_factor()?;
+        let mut lhs = self.parse_cast()?;
         while matches!(self.peek(), Token::Star | Token::Slash | Token::Percent) {
             let op = match self.peek() {
                 Token::Star => BinOp::Mul,
@@ -266,19 +486,56 @@ impl Parser {
                 Token::Percent => BinOp::Rem,
                 _ => unreachable!(),
             };
+            let line = self.line();
             self.eat();
-            let rhs = self.parse_factor()?;
-            lhs = Expr::Binary {
+            let rhs = self.parse_cast()?;
+            lhs = Expr::new(ExprKind::Binary {
                 op,
                 left: Box::new(lhs),
                 right: Box::new(rhs),
-            };
+                line,
+            });
         }
         Ok(lhs)
     }
 
+    /// `factor as i32 as u8 …`
+    fn parse_cast(&mut self) -> Result<Expr, CompileError> {
+        let mut node = self.parse_factor()?;
+        while *self.peek() == Token::As {
+            self.eat();
+            let to = match self.peek() {
+                Token::Ident(name) => IntKind::from_name(name).ok_or_else(|| {
+                    CompileError::Parse(format!("Cannot cast to {}, only to integer types", name))
+                })?,
+                other => {
+                    return Err(CompileError::Parse(format!(
+                        "Expected an integer type after 'as', found {:?}",
+                        other
+                    )));
+                }
+            };
+            self.eat();
+            node = Expr::new(ExprKind::Cast {
+                expr: Box::new(node),
+                to,
+            });
+        }
+        Ok(node)
+    }
+
     fn parse_factor(&mut self) -> Result<Expr, CompileError> {
         let mut node = match self.peek() {
+            Token::Bang => {
+                let line = self.line();
+                self.eat();
+                let operand = self.parse_factor()?;
+                return Ok(Expr::new(ExprKind::Unary {
+                    op: UnOp::Not,
+                    expr: Box::new(operand),
+                    line,
+                }));
+            }
             Token::LBracket => {
                 self.eat(); // consume '['
                 let mut elems = Vec::new();
@@ -294,22 +551,23 @@ impl Parser {
                     }
                 }
                 self.expect(Token::RBracket)?;
-                Expr::ArrayLiteral(elems)
+                Expr::new(ExprKind::ArrayLiteral(elems))
             }
             Token::Number(n) => {
                 let v = *n;
                 self.eat();
-                Expr::Number(v)
+                // The type checker settles the actual type
+                Expr::new(ExprKind::Number(v.into(), IntKind::I64))
             }
             Token::BoolLiteral(b) => {
                 let v = *b;
                 self.eat();
-                Expr::Bool(v)
+                Expr::new(ExprKind::Bool(v))
             }
             Token::StrLiteral(s) => {
                 let v = s.clone();
                 self.eat();
-                Expr::StrLiteral(v)
+                Expr::new(ExprKind::StrLiteral(v))
             }
             Token::Ident(name) => {
                 let name = name.clone();
@@ -329,16 +587,27 @@ impl Parser {
                         }
                     }
                     self.expect(Token::RParen)?;
-                    Expr::Call { name, args }
+                    Expr::new(ExprKind::Call { name, args })
                 } else {
-                    Expr::Variable(name)
+                    Expr::new(ExprKind::Variable(name))
                 }
             }
             Token::LParen => {
                 self.eat();
                 let e = self.parse_expr()?;
-                self.expect(Token::RParen)?;
-                e
+                if *self.peek() == Token::Comma {
+                    // `(a, b, ...)` is a tuple, `(a)` just groups
+                    let mut elems = vec![e];
+                    while *self.peek() == Token::Comma {
+                        self.eat();
+                        elems.push(self.parse_expr()?);
+                    }
+                    self.expect(Token::RParen)?;
+                    Expr::new(ExprKind::Tuple(elems))
+                } else {
+                    self.expect(Token::RParen)?;
+                    e
+                }
             }
             other => {
                 return Err(CompileError::Parse(format!(
@@ -351,28 +620,54 @@ impl Parser {
         loop {
             match self.peek() {
                 Token::LBracket => {
+                    let line = self.line();
                     self.eat();
                     let idx = self.parse_expr()?;
                     self.expect(Token::RBracket)?;
-                    node = Expr::Index {
+                    node = Expr::new(ExprKind::Index {
                         array: Box::new(node),
                         index: Box::new(idx),
-                    };
+                        line,
+                    });
                 }
                 Token::Dot => {
+                    let line = self.line();
                     self.eat();
                     match self.peek() {
                         Token::Ident(method_name) if method_name == "length" => {
                             self.eat();
                             self.expect(Token::LParen)?;
                             self.expect(Token::RParen)?;
-                            node = Expr::Length {
+                            node = Expr::new(ExprKind::Length {
                                 array: Box::new(node),
-                            };
+                            });
+                        }
+                        Token::Ident(method_name) => {
+                            let method = method_name.clone();
+                            self.eat();
+                            self.expect(Token::LParen)?;
+                            let mut args = Vec::new();
+                            if *self.peek() != Token::RParen {
+                                loop {
+                                    args.push(self.parse_expr()?);
+                                    if *self.peek() == Token::Comma {
+                                        self.eat();
+                                        continue;
+                                    }
+                                    break;
+                                }
+                            }
+                            self.expect(Token::RParen)?;
+                            node = Expr::new(ExprKind::MethodCall {
+                                receiver: Box::new(node),
+                                method,
+                                args,
+                                line,
+                            });
                         }
                         other => {
                             return Err(CompileError::Parse(format!(
-                                "Unexpected token after '.', expected 'length', found {:?}",
+                                "Unexpected token after '.', expected a method name, found {:?}",
                                 other
                             )));
                         }
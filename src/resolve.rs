@@ -0,0 +1,685 @@
+use crate::ast::*;
+use crate::error::CompileError;
+use crate::lint::{Lint, Lints};
+use std::collections::{HashMap, HashSet};
+
+/// Functions the code generator provides, with the number of arguments they take.
+const BUILTINS: &[(&str, usize)] = &[
+    ("length", 1),
+    ("list", 0),
+    ("to_string", 1),
+    ("parse_int", 1),
+    ("wrapping_add", 2),
+    ("wrapping_sub", 2),
+    ("wrapping_mul", 2),
+];
+
+/// Check that every name a program uses refers to a declaration, reporting all
+/// problems at once before any code is generated. Declarations that are never
+/// used, and calls whose result is thrown away, are reported to `lints`.
+pub fn resolve(prog: &Program, lints: &mut Lints) -> Result<(), CompileError> {
+    let mut resolver = Resolver {
+        functions: HashMap::new(),
+        pure: pure_functions(prog),
+        called: HashSet::new(),
+        locals: Vec::new(),
+        function: "",
+        scope: String::new(),
+        errors: Vec::new(),
+        lints,
+    };
+    resolver.declare_functions(prog);
+    for func in &prog.functions {
+        resolver.resolve_function(func);
+    }
+    resolver.function = "";
+    resolver.scope = "top-level code".into();
+    resolver.locals = vec![HashMap::new()];
+    for stmt in &prog.statements {
+        resolver.resolve_statement(stmt);
+    }
+    resolver.pop_scope();
+
+    for func in &prog.functions {
+        if !resolver.called.contains(&func.name) && !func.name.starts_with('_') {
+            let msg = format!("Function {} is never called", func.name);
+            resolver.lints.report(Lint::UnusedFunctions, msg);
+        }
+    }
+
+    if resolver.errors.is_empty() {
+        Ok(())
+    } else {
+        Err(CompileError::Resolve(resolver.errors))
+    }
+}
+
+struct Resolver<'a, 'l> {
+    functions: HashMap<&'a str, usize>, // name -> number of parameters
+    pure: HashSet<&'a str>,             // functions whose only effect is their result
+    called: HashSet<String>,            // functions called from outside their own body
+    locals: Vec<HashMap<String, Binding>>, // one map per enclosing block, innermost last
+    function: &'a str,                  // the function being resolved, if any
+    scope: String,                      // where we are, for error messages
+    errors: Vec<String>,
+    lints: &'l mut Lints,
+}
+
+/// A declared variable: where it was declared, how, and what has been done with it
+/// so far.
+#[derive(Clone, Copy)]
+struct Binding {
+    kind: Kind,
+    line: usize,
+    used: bool,
+    reassigned: bool,
+}
+
+/// How a variable was declared. `var`s and parameters may be assigned to, `let`s may not.
+///
+/// A `let` only guards its own name: nothing can be assigned to it, to its elements, or
+/// pushed to or popped from it through that name. Arrays and lists are shared by
+/// reference, so a `var` or parameter holding the same one can still modify it.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Parameter,
+    Var,
+    Let,
+}
+
+impl Binding {
+    fn new(kind: Kind, line: usize) -> Self {
+        Binding {
+            kind,
+            line,
+            used: false,
+            reassigned: false,
+        }
+    }
+}
+
+impl<'a> Resolver<'a, '_> {
+    /// Build the function symbol table, flagging names declared twice.
+    fn declare_functions(&mut self, prog: &'a Program) {
+        for func in &prog.functions {
+            if BUILTINS.iter().any(|(name, _)| *name == func.name) {
+                self.errors
+                    .push(format!("Function {} redefines a builtin", func.name));
+            } else if self.functions.contains_key(func.name.as_str()) {
+                self.errors
+                    .push(format!("Function {} is defined more than once", func.name));
+            } else {
+                self.functions.insert(&func.name, func.params.len());
+            }
+        }
+    }
+
+    fn resolve_function(&mut self, func: &'a Function) {
+        self.function = &func.name;
+        self.scope = format!("function {}", func.name);
+        self.locals = vec![HashMap::new()];
+        let names = func.params.iter().flat_map(|p| p.pattern.names()).collect();
+        self.declare(names, "parameter", Binding::new(Kind::Parameter, func.line));
+        for stmt in &func.body {
+            self.resolve_statement(stmt);
+        }
+        self.pop_scope();
+    }
+
+    fn resolve_statement(&mut self, stmt: &Statement) {
+        match stmt {
+            Statement::VarDecl {
+                name, expr, line, ..
+            } => {
+                if let Some(expr) = expr {
+                    self.resolve_expr(expr);
+                }
+                // Only `let` may shadow
+                if let Some(prev) = self.lookup(name) {
+                    self.errors.push(format!(
+                        "var {} at line {} in {} redeclares {} from line {}; \
+                         assign to it, or use let to shadow it",
+                        name, line, self.scope, name, prev.line
+                    ));
+                }
+                let mut binding = Binding::new(Kind::Var, *line);
+                // Without an initializer it could not have been a `let`
+                binding.reassigned = expr.is_none();
+                self.declare(vec![name], "variable", binding);
+            }
+            Statement::LetDecl {
+                name, expr, line, ..
+            } => {
+                self.resolve_expr(expr);
+                self.declare(vec![name], "variable", Binding::new(Kind::Let, *line));
+            }
+            Statement::LetPattern {
+                pattern,
+                expr,
+                line,
+            } => {
+                self.resolve_expr(expr);
+                let names = pattern.names();
+                self.declare(names, "binding", Binding::new(Kind::Let, *line));
+            }
+            Statement::Assign { name, expr, line } => {
+                self.resolve_expr(expr);
+                match self.lookup_mut(name) {
+                    None => self.errors.push(format!(
+                        "Assignment to undeclared variable {} at line {} in {}",
+                        name, line, self.scope
+                    )),
+                    Some(binding) => {
+                        binding.reassigned = true;
+                        let binding = *binding;
+                        self.check_mutable(name, binding, "assign to", *line);
+                    }
+                }
+            }
+            Statement::IndexedAssign {
+                array,
+                index,
+                expr,
+                line,
+            } => {
+                self.resolve_expr(array);
+                self.resolve_expr(index);
+                self.resolve_expr(expr);
+                self.check_modified(array, "assign to an element of", *line);
+            }
+            Statement::ExprStmt(expr) => {
+                if let ExprKind::Call { name, .. } = &expr.kind
+                    && self.pure.contains(name.as_str())
+                {
+                    let msg = format!("Result of call to {} is unused in {}", name, self.scope);
+                    self.lints.report(Lint::UnusedResults, msg);
+                }
+                self.resolve_expr(expr);
+            }
+            Statement::Return { expr, .. } | Statement::Print { expr } => {
+                self.resolve_expr(expr);
+            }
+            Statement::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.resolve_expr(cond);
+                self.resolve_block(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve_block(else_branch);
+                }
+            }
+            Statement::While { cond, body, .. } => {
+                self.resolve_expr(cond);
+                self.resolve_block(body);
+            }
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Number(..) | ExprKind::Bool(_) | ExprKind::StrLiteral(_) => {}
+            ExprKind::Variable(name) => match self.lookup_mut(name) {
+                Some(binding) => binding.used = true,
+                None => self
+                    .errors
+                    .push(format!("Undefined variable {} in {}", name, self.scope)),
+            },
+            ExprKind::Call { name, args } => {
+                let arity = BUILTINS
+                    .iter()
+                    .find(|(builtin, _)| builtin == name)
+                    .map(|(_, arity)| *arity)
+                    .or_else(|| self.functions.get(name.as_str()).copied());
+                match arity {
+                    Some(arity) if arity != args.len() => self.errors.push(format!(
+                        "{} takes {} arguments but {} were given in {}",
+                        name,
+                        arity,
+                        args.len(),
+                        self.scope
+                    )),
+                    Some(_) => {}
+                    None => self
+                        .errors
+                        .push(format!("Unknown function {} in {}", name, self.scope)),
+                }
+                if name != self.function {
+                    self.called.insert(name.clone());
+                }
+                for arg in args {
+                    self.resolve_expr(arg);
+                }
+            }
+            ExprKind::Unary { expr, .. } | ExprKind::Cast { expr, .. } => self.resolve_expr(expr),
+            ExprKind::Binary { left, right, .. } => {
+                self.resolve_expr(left);
+                self.resolve_expr(right);
+            }
+            ExprKind::ArrayLiteral(elems) | ExprKind::Tuple(elems) => {
+                for e in elems {
+                    self.resolve_expr(e);
+                }
+            }
+            ExprKind::Index { array, index, .. } => {
+                self.resolve_expr(array);
+                self.resolve_expr(index);
+            }
+            ExprKind::Length { array } => self.resolve_expr(array),
+            ExprKind::MethodCall {
+                receiver,
+                method,
+                args,
+                line,
+            } => {
+                self.resolve_expr(receiver);
+                for arg in args {
+                    self.resolve_expr(arg);
+                }
+                // every list method changes the list
+                self.check_modified(receiver, &format!("call .{}() on", method), *line);
+            }
+        }
+    }
+
+    /// Resolve a nested block, whose declarations go out of scope at its end.
+    fn resolve_block(&mut self, stmts: &[Statement]) {
+        self.locals.push(HashMap::new());
+        for stmt in stmts {
+            self.resolve_statement(stmt);
+        }
+        self.pop_scope();
+    }
+
+    /// Leave the innermost scope, reporting what was declared in it and never used.
+    fn pop_scope(&mut self) {
+        let scope = self.locals.pop().expect("no scope to leave");
+        let mut bindings: Vec<_> = scope.into_iter().collect();
+        bindings.sort_by(|(a, x), (b, y)| (x.line, a).cmp(&(y.line, b)));
+        for (name, binding) in bindings {
+            self.report_unused(&name, binding);
+        }
+    }
+
+    /// Report `name` if it went unused, or was declared with `var` but never assigned to.
+    fn report_unused(&mut self, name: &str, binding: Binding) {
+        if name.starts_with('_') {
+            return;
+        }
+        if !binding.used {
+            let (lint, msg) = match binding.kind {
+                Kind::Parameter => (
+                    Lint::UnusedParameters,
+                    format!("Unused parameter {} in {}", name, self.scope),
+                ),
+                Kind::Var | Kind::Let => (
+                    Lint::UnusedVariables,
+                    format!(
+                        "Unused variable {} declared at line {} in {}",
+                        name, binding.line, self.scope
+                    ),
+                ),
+            };
+            self.lints.report(lint, msg);
+        } else if binding.kind == Kind::Var && !binding.reassigned {
+            let msg = format!(
+                "var {} at line {} in {} is never reassigned; declare it with let",
+                name, binding.line, self.scope
+            );
+            self.lints.report(Lint::NeverReassigned, msg);
+        }
+    }
+
+    /// The innermost visible declaration of `name`.
+    fn lookup(&self, name: &str) -> Option<Binding> {
+        self.locals
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name))
+            .copied()
+    }
+
+    fn lookup_mut(&mut self, name: &str) -> Option<&mut Binding> {
+        self.locals
+            .iter_mut()
+            .rev()
+            .find_map(|scope| scope.get_mut(name))
+    }
+
+    /// Mark the variable that `place` is part of as modified, which `let` forbids.
+    fn check_modified(&mut self, place: &Expr, action: &str, line: usize) {
+        // `a[i][j] = v` and `a[i].push(v)` modify `a`
+        let mut root = place;
+        while let ExprKind::Index { array, .. } = &root.kind {
+            root = array;
+        }
+        if let ExprKind::Variable(name) = &root.kind
+            && let Some(binding) = self.lookup_mut(name)
+        {
+            binding.reassigned = true;
+            let binding = *binding;
+            self.check_mutable(name, binding, action, line);
+        }
+    }
+
+    fn check_mutable(&mut self, name: &str, binding: Binding, action: &str, line: usize) {
+        if binding.kind == Kind::Let {
+            self.errors.push(format!(
+                "Cannot {} {} at line {} in {}: it is declared with let at line {}; \
+                 declare it with var to make it mutable",
+                action, name, line, self.scope, binding.line
+            ));
+        }
+    }
+
+    /// Declare names bound together, flagging any that appear twice among them.
+    fn declare(&mut self, names: Vec<&str>, what: &str, binding: Binding) {
+        let scope = self
+            .locals
+            .last_mut()
+            .expect("declaration outside any scope");
+        let mut seen = HashSet::new();
+        let mut shadowed = Vec::new();
+        for name in names {
+            if !seen.insert(name) {
+                self.errors
+                    .push(format!("Duplicate {} {} in {}", what, name, self.scope));
+            } else if let Some(prev) = scope.insert(name.to_string(), binding) {
+                shadowed.push((name, prev));
+            }
+        }
+        // A `let` in the same block hides the earlier declaration for good
+        for (name, prev) in shadowed {
+            self.report_unused(name, prev);
+        }
+    }
+}
+
+/// Functions whose only effect is their result: they print nothing, modify no
+/// array or list, and call only functions like themselves.
+fn pure_functions(prog: &Program) -> HashSet<&str> {
+    let mut pure: HashSet<&str> = BUILTINS
+        .iter()
+        .map(|(name, _)| *name)
+        .chain(prog.functions.iter().map(|f| f.name.as_str()))
+        .collect();
+    // Start from everything and rule functions out until nothing changes
+    loop {
+        let impure: Vec<&str> = prog
+            .functions
+            .iter()
+            .filter(|f| pure.contains(f.name.as_str()))
+            .filter(|f| !f.body.iter().all(|s| statement_is_pure(s, &pure)))
+            .map(|f| f.name.as_str())
+            .collect();
+        if impure.is_empty() {
+            return pure;
+        }
+        for name in impure {
+            pure.remove(name);
+        }
+    }
+}
+
+fn statement_is_pure(stmt: &Statement, pure: &HashSet<&str>) -> bool {
+    match stmt {
+        Statement::Print { .. } | Statement::IndexedAssign { .. } => false,
+        Statement::VarDecl { expr, .. } => expr.iter().all(|e| expr_is_pure(e, pure)),
+        Statement::LetDecl { expr, .. }
+        | Statement::LetPattern { expr, .. }
+        | Statement::Assign { expr, .. }
+        | Statement::Return { expr, .. }
+        | Statement::ExprStmt(expr) => expr_is_pure(expr, pure),
+        Statement::If {
+            cond,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            expr_is_pure(cond, pure)
+                && then_branch
+                    .iter()
+                    .chain(else_branch.iter().flatten())
+                    .all(|s| statement_is_pure(s, pure))
+        }
+        Statement::While { cond, body, .. } => {
+            expr_is_pure(cond, pure) && body.iter().all(|s| statement_is_pure(s, pure))
+        }
+    }
+}
+
+fn expr_is_pure(expr: &Expr, pure: &HashSet<&str>) -> bool {
+    match &expr.kind {
+        ExprKind::Number(..)
+        | ExprKind::Bool(_)
+        | ExprKind::StrLiteral(_)
+        | ExprKind::Variable(_) => true,
+        ExprKind::Unary { expr, .. }
+        | ExprKind::Cast { expr, .. }
+        | ExprKind::Length { array: expr } => expr_is_pure(expr, pure),
+        ExprKind::Binary { left, right, .. }
+        | ExprKind::Index {
+            array: left,
+            index: right,
+            ..
+        } => expr_is_pure(left, pure) && expr_is_pure(right, pure),
+        ExprKind::ArrayLiteral(elems) | ExprKind::Tuple(elems) => {
+            elems.iter().all(|e| expr_is_pure(e, pure))
+        }
+        ExprKind::Call { name, args } => {
+            pure.contains(name.as_str()) && args.iter().all(|e| expr_is_pure(e, pure))
+        }
+        // Every list method modifies the list
+        ExprKind::MethodCall { .. } => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::lint::Level;
+    use crate::parser::Parser;
+
+    /// The resolve errors in `src`, and the lints it raises.
+    fn analyse(src: &str) -> (Vec<String>, Vec<String>) {
+        let prog = Parser::new(lex(src).unwrap()).parse_program().unwrap();
+        let mut lints = Lints::default();
+        lints.set_level("warnings", Level::Deny).unwrap();
+        let errors = match resolve(&prog, &mut lints) {
+            Ok(()) => Vec::new(),
+            Err(CompileError::Resolve(msgs)) => msgs,
+            Err(e) => panic!("expected resolve errors, got {}", e),
+        };
+        let warnings = match lints.check() {
+            Ok(()) => Vec::new(),
+            Err(CompileError::Lint(msgs)) => msgs,
+            Err(e) => panic!("expected lint errors, got {}", e),
+        };
+        (errors, warnings)
+    }
+
+    fn errors(src: &str) -> Vec<String> {
+        analyse(src).0
+    }
+
+    fn warnings(src: &str) -> Vec<String> {
+        analyse(src).1
+    }
+
+    #[test]
+    fn every_undefined_name_is_reported() {
+        let errors = errors("print(a); print(f(b));");
+        assert_eq!(
+            errors,
+            [
+                "Undefined variable a in top-level code",
+                "Unknown function f in top-level code",
+                "Undefined variable b in top-level code",
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_must_match_the_arity() {
+        let errors = errors("fn g(x) { return x; } print(g(1, 2)); print(length());");
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert!(
+            errors[0].contains("g takes 1 arguments but 2 were given"),
+            "{}",
+            errors[0]
+        );
+        assert!(
+            errors[1].contains("length takes 1 arguments but 0 were given"),
+            "{}",
+            errors[1]
+        );
+    }
+
+    #[test]
+    fn functions_are_declared_once() {
+        let errors = errors(
+            "fn f() { return 0; } fn f() { return 1; } fn length(x) { return x; }
+             print(f());",
+        );
+        assert_eq!(
+            errors,
+            [
+                "Function f is defined more than once",
+                "Function length redefines a builtin",
+            ]
+        );
+    }
+
+    #[test]
+    fn names_bound_together_are_distinct() {
+        let errors = errors("let (a, a) = (1, 2); print(a);");
+        assert_eq!(errors, ["Duplicate binding a in top-level code"]);
+    }
+
+    #[test]
+    fn lets_cannot_be_modified() {
+        let errors =
+            errors("let x = 1;\nx = 2;\nlet a = [1];\na[0] = 2;\nlet xs = list();\nxs.push(1);");
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(
+            errors[0].starts_with("Cannot assign to x at line 2"),
+            "{}",
+            errors[0]
+        );
+        assert!(
+            errors[1].starts_with("Cannot assign to an element of a at line 4"),
+            "{}",
+            errors[1]
+        );
+        assert!(
+            errors[2].starts_with("Cannot call .push() on xs at line 6"),
+            "{}",
+            errors[2]
+        );
+    }
+
+    #[test]
+    fn vars_and_parameters_can_be_modified() {
+        let src = "fn f(a, xs) { a = 1; xs.push(a); return a; }
+                   var n = 1; n = 2; var ys = list(); ys.push(n); var g = [[1]]; g[0][0] = 2;
+                   print(f(n, ys) + g[0][0]);";
+        assert_eq!(errors(src), Vec::<String>::new());
+    }
+
+    #[test]
+    fn declarations_end_with_their_block() {
+        let errors = errors(
+            "if (true) { let y = 1; print(y); }
+             while (false) { var z = 2; z = 3; print(z); }
+             print(y + z);",
+        );
+        assert_eq!(
+            errors,
+            [
+                "Undefined variable y in top-level code",
+                "Undefined variable z in top-level code",
+            ]
+        );
+    }
+
+    #[test]
+    fn lets_may_shadow_any_visible_name() {
+        let src = "fn f(n) { let n = n * 2; return n; }
+                   let x = 1; let x = x + 1; var v = 0;
+                   if (true) { let x = x * 3; let v = x; print(v); }
+                   v = x; print(f(v));";
+        assert_eq!(errors(src), Vec::<String>::new());
+    }
+
+    #[test]
+    fn vars_may_not_redeclare_visible_names() {
+        let errors = errors("let x = 1;\nif (true) {\nvar x = 2;\nx = 3;\nprint(x);\n}\nprint(x);");
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(
+            errors[0].starts_with("var x at line 3 in top-level code redeclares x from line 1"),
+            "{}",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn unused_declarations_are_linted() {
+        let warnings = warnings("fn f(a) { return 1; }\nlet x = 1;\nlet _y = 2;");
+        assert_eq!(
+            warnings,
+            [
+                "Unused parameter a in function f [unused_parameters]",
+                "Unused variable x declared at line 2 in top-level code [unused_variables]",
+                "Function f is never called [unused_functions]",
+            ]
+        );
+    }
+
+    #[test]
+    fn shadowed_lets_must_have_been_used() {
+        let warnings = warnings("let x = 1;\nlet x = 2;\nprint(x);");
+        assert_eq!(
+            warnings,
+            ["Unused variable x declared at line 1 in top-level code [unused_variables]"]
+        );
+    }
+
+    #[test]
+    fn vars_never_reassigned_are_linted() {
+        let warnings = warnings("var x = 1;\nvar ys = list();\nys.push(x);\nprint(x);");
+        // `ys` is modified by `push`, which counts
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(
+            warnings[0].starts_with("var x at line 1 in top-level code is never reassigned"),
+            "{}",
+            warnings[0]
+        );
+    }
+
+    #[test]
+    fn unused_results_of_pure_calls_are_linted() {
+        let warnings = warnings(
+            "fn sq(x) { return x * x; } fn show(x) { print(x); return 0; }
+             sq(2); show(3); to_string(4);",
+        );
+        assert_eq!(
+            warnings,
+            [
+                "Result of call to sq is unused in top-level code [unused_results]",
+                "Result of call to to_string is unused in top-level code [unused_results]",
+            ]
+        );
+    }
+
+    #[test]
+    fn lets_do_not_guard_their_aliases() {
+        // Arrays and lists are references; `let` only fixes the name `a`
+        let src = "fn g(xs) { xs[0] = 9; return 0; }
+                   let a = [1, 2]; var b = a; b[0] = 9; print(g(a));
+                   let l = list(); var m = l; m.push(1); print(l.length());";
+        assert_eq!(errors(src), Vec::<String>::new());
+    }
+}
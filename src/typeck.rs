@@ -0,0 +1,1265 @@
+use crate::ast::*;
+use crate::error::CompileError;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::vec;
+
+/// Infer the type of every function, parameter, declaration and expression by
+/// unification, rejecting ill-typed programs and writing the inferred types back
+/// into the AST.
+///
+/// Generic functions are checked once per set of type arguments they are called
+/// with. Each such instance becomes a function of its own, named like `max<int>`,
+/// which takes the generic function's place in the program and at its call sites.
+pub fn check(prog: &mut Program) -> Result<(), CompileError> {
+    let mut checker = Checker {
+        subst: Vec::new(),
+        functions: HashMap::new(),
+        locals: HashMap::new(),
+        return_ty: Ty::INT,
+        scope: String::new(),
+        checks: Vec::new(),
+        expr_types: Vec::new(),
+        decl_types: Vec::new(),
+        type_args: Vec::new(),
+        calls: Vec::new(),
+        depth: 0,
+    };
+    let (generics, mut functions): (Vec<Function>, Vec<Function>) =
+        std::mem::take(&mut prog.functions)
+            .into_iter()
+            .partition(|func| !func.type_params.is_empty());
+    for func in generics.iter().chain(&functions) {
+        checker.declare_function(func);
+    }
+    for func in &functions {
+        checker.check_function(func)?;
+    }
+    checker.scope = "top-level code".into();
+    checker.locals.clear();
+    checker.return_ty = Ty::INT;
+    checker.check_block(&prog.statements)?;
+
+    // Check generic functions once their type arguments are known. Defaults are
+    // applied only when nothing else can be learned, so that an instance may still
+    // constrain the functions it calls
+    let generics: HashMap<&str, &Function> = generics
+        .iter()
+        .map(|func| (func.name.as_str(), func))
+        .collect();
+    let mut instances = Vec::new();
+    let mut instantiated = HashSet::new();
+    loop {
+        checker.solve_checks()?;
+        if let Some(call) = checker.next_call() {
+            let args: Vec<Type> = call
+                .type_args
+                .iter()
+                .map(|arg| checker.to_type(arg))
+                .collect();
+            let instance_name = instance_name(&call.name, &args);
+            if instantiated.insert(instance_name.clone()) {
+                // `f<T>` calling `f((x, x))` would need ever larger instances
+                if call.depth == MAX_INSTANCE_DEPTH {
+                    return Err(CompileError::Type(format!(
+                        "Generic function {} is instantiated more than {} calls deep; \
+                         it cannot call itself with ever larger types",
+                        call.name, MAX_INSTANCE_DEPTH
+                    )));
+                }
+                let func = instance(generics[call.name.as_str()], instance_name, &args);
+                checker.declare_function(&func);
+                checker.depth = call.depth + 1;
+                checker.check_function(&func)?;
+                instances.push(func);
+            }
+        } else if !checker.checks.is_empty() {
+            let check = checker.checks.remove(0);
+            checker.default_check(&check)?;
+            checker.checks.push(check);
+        } else if let Some(call) = checker.calls.first() {
+            // Type arguments nothing constrains are ints
+            for arg in call.type_args.clone() {
+                checker.default_vars(&arg);
+            }
+        } else {
+            break;
+        }
+    }
+
+    // Write the solved types back; anything still unconstrained is an int
+    for func in &mut functions {
+        let sig = &checker.functions[&func.name];
+        for (param, ty) in func.params.iter_mut().zip(&sig.params) {
+            param.ty = Some(checker.to_type(ty));
+        }
+        func.return_type = Some(checker.to_type(&sig.ret));
+    }
+    let mut annotator = checker.annotator();
+    for func in &mut functions {
+        annotator.block(&mut func.body);
+    }
+    annotator.block(&mut prog.statements);
+    for func in &mut instances {
+        annotator.block(&mut func.body);
+    }
+    functions.extend(instances);
+    prog.functions = functions;
+    Ok(())
+}
+
+/// How deeply instances may call further instances. Type arguments can double in
+/// size at each level, so this is kept small.
+const MAX_INSTANCE_DEPTH: usize = 10;
+
+/// What the instance of generic function `name` for type arguments `args` is called.
+fn instance_name(name: &str, args: &[Type]) -> String {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    format!("{}<{}>", name, args.join(", "))
+}
+
+/// A copy of generic function `func` with its type parameters replaced by `args`.
+fn instance(func: &Function, name: String, args: &[Type]) -> Function {
+    let bindings: HashMap<String, Type> = func
+        .type_params
+        .iter()
+        .cloned()
+        .zip(args.iter().cloned())
+        .collect();
+    // Unannotated parameters and results of a generic function are plain ints
+    let substitute =
+        |ty: &Option<Type>| Some(ty.as_ref().map_or(Type::INT, |ty| ty.substitute(&bindings)));
+    Function {
+        name,
+        line: func.line,
+        type_params: Vec::new(),
+        params: func
+            .params
+            .iter()
+            .map(|param| Param {
+                pattern: param.pattern.clone(),
+                ty: substitute(&param.ty),
+            })
+            .collect(),
+        return_type: substitute(&func.return_type),
+        body: substitute_annotations(&func.body, &bindings),
+    }
+}
+
+/// A copy of `stmts` with the type parameters in its annotations replaced.
+fn substitute_annotations(stmts: &[Statement], bindings: &HashMap<String, Type>) -> Vec<Statement> {
+    let mut stmts = stmts.to_vec();
+    for stmt in &mut stmts {
+        match stmt {
+            Statement::VarDecl { ty: Some(ty), .. } | Statement::LetDecl { ty: Some(ty), .. } => {
+                *ty = ty.substitute(bindings);
+            }
+            Statement::If {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                *then_branch = substitute_annotations(then_branch, bindings);
+                if let Some(else_branch) = else_branch {
+                    *else_branch = substitute_annotations(else_branch, bindings);
+                }
+            }
+            Statement::While { body, .. } => *body = substitute_annotations(body, bindings),
+            _ => {}
+        }
+    }
+    stmts
+}
+
+/// Writes solved types back into the AST, visiting it in the order it was checked.
+struct Annotator {
+    exprs: vec::IntoIter<Type>,
+    decls: vec::IntoIter<Type>,
+    /// The type arguments of every call, empty unless the callee is generic.
+    type_args: vec::IntoIter<Vec<Type>>,
+}
+
+impl Annotator {
+    fn block(&mut self, stmts: &mut [Statement]) {
+        for stmt in stmts {
+            self.statement(stmt);
+        }
+    }
+
+    fn statement(&mut self, stmt: &mut Statement) {
+        match stmt {
+            Statement::VarDecl { ty, expr, .. } => {
+                if let Some(expr) = expr {
+                    self.expr(expr);
+                }
+                *ty = self.decls.next();
+            }
+            Statement::LetDecl { ty, expr, .. } => {
+                self.expr(expr);
+                *ty = self.decls.next();
+            }
+            Statement::LetPattern { expr, .. }
+            | Statement::Assign { expr, .. }
+            | Statement::Return { expr, .. }
+            | Statement::Print { expr }
+            | Statement::ExprStmt(expr) => self.expr(expr),
+            Statement::IndexedAssign {
+                array, index, expr, ..
+            } => {
+                self.expr(array);
+                self.expr(index);
+                self.expr(expr);
+            }
+            Statement::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                self.expr(cond);
+                self.block(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.block(else_branch);
+                }
+            }
+            Statement::While { cond, body, .. } => {
+                self.expr(cond);
+                self.block(body);
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match &mut expr.kind {
+            ExprKind::Number(..)
+            | ExprKind::Bool(_)
+            | ExprKind::StrLiteral(_)
+            | ExprKind::Variable(_) => {}
+            ExprKind::Unary { expr, .. }
+            | ExprKind::Cast { expr, .. }
+            | ExprKind::Length { array: expr } => self.expr(expr),
+            ExprKind::Binary { left, right, .. }
+            | ExprKind::Index {
+                array: left,
+                index: right,
+                ..
+            } => {
+                self.expr(left);
+                self.expr(right);
+            }
+            ExprKind::Call { name, args } => {
+                for arg in args.iter_mut() {
+                    self.expr(arg);
+                }
+                let type_args = self.type_args.next().expect("every call was checked");
+                if !type_args.is_empty() {
+                    *name = instance_name(name, &type_args);
+                }
+            }
+            ExprKind::ArrayLiteral(elems) | ExprKind::Tuple(elems) => {
+                for e in elems {
+                    self.expr(e);
+                }
+            }
+            ExprKind::MethodCall { receiver, args, .. } => {
+                self.expr(receiver);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+        }
+        let ty = self.exprs.next().expect("every expression was checked");
+        if let ExprKind::Number(_, kind) = &mut expr.kind {
+            let Type::Int(solved) = ty else {
+                unreachable!("integer literal checked to be an integer")
+            };
+            *kind = solved;
+        }
+        expr.ty = Some(ty);
+    }
+}
+
+/// A type during inference: either a known shape or a variable still to be solved.
+#[derive(Debug, Clone, PartialEq)]
+enum Ty {
+    Var(usize),
+    Int(IntKind),
+    Bool,
+    Str,
+    Array(Box<Ty>),
+    List(Box<Ty>),
+    Tuple(Vec<Ty>),
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ty::Var(_) => write!(f, "_"),
+            Ty::Int(kind) => write!(f, "{}", kind),
+            Ty::Bool => write!(f, "bool"),
+            Ty::Str => write!(f, "string"),
+            Ty::Array(elem) => write!(f, "[{}]", elem),
+            Ty::List(elem) => write!(f, "list<{}>", elem),
+            Ty::Tuple(elems) => {
+                write!(f, "(")?;
+                for (i, elem) in elems.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", elem)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl Ty {
+    const INT: Ty = Ty::Int(IntKind::I64);
+}
+
+/// A function's type. For generic functions, `type_params` are the variables
+/// standing for its type parameters, replaced by fresh ones at every call.
+struct Signature {
+    type_params: Vec<usize>,
+    params: Vec<Ty>,
+    ret: Ty,
+}
+
+/// A constraint that can only be checked once more is known about a type,
+/// because the operation it comes from works on several kinds of value.
+enum Check {
+    /// `container[i]` yields or stores `elem`.
+    Index {
+        container: Ty,
+        elem: Ty,
+        writable: bool,
+        scope: String,
+    },
+    Length {
+        container: Ty,
+        scope: String,
+    },
+    Operator {
+        op: BinOp,
+        operand: Ty,
+        scope: String,
+    },
+    Print {
+        ty: Ty,
+        scope: String,
+    },
+    /// An integer literal, index or operand of unary minus, of any integer type.
+    Integer {
+        ty: Ty,
+        scope: String,
+    },
+    /// The operand of `as`, an integer or bool.
+    Cast {
+        from: Ty,
+        scope: String,
+    },
+}
+
+struct Checker {
+    subst: Vec<Option<Ty>>, // what each type variable has been solved to
+    functions: HashMap<String, Signature>,
+    locals: HashMap<String, Ty>,
+    return_ty: Ty,
+    scope: String, // where we are, for error messages
+    checks: Vec<Check>,
+    expr_types: Vec<Ty>,     // the type of every expression, in checking order
+    decl_types: Vec<Ty>,     // the type of every declaration, in checking order
+    type_args: Vec<Vec<Ty>>, // the type arguments of every call, in checking order
+    calls: Vec<PendingCall>,
+    depth: usize, // how many instances deep the code being checked is
+}
+
+/// A call of a generic function whose instance is yet to be checked.
+struct PendingCall {
+    name: String,
+    type_args: Vec<Ty>,
+    /// How many instances deep the call is, 0 outside generic code.
+    depth: usize,
+}
+
+impl Checker {
+    fn fresh(&mut self) -> Ty {
+        self.subst.push(None);
+        Ty::Var(self.subst.len() - 1)
+    }
+
+    /// Convert an annotation, mapping type parameters through `params`.
+    fn annotation(ty: &Type, params: &HashMap<String, Ty>) -> Ty {
+        match ty {
+            Type::Int(kind) => Ty::Int(*kind),
+            Type::Bool => Ty::Bool,
+            Type::Str => Ty::Str,
+            Type::Array(elem) => Ty::Array(Box::new(Self::annotation(elem, params))),
+            Type::List(elem) => Ty::List(Box::new(Self::annotation(elem, params))),
+            Type::Tuple(elems) => {
+                Ty::Tuple(elems.iter().map(|t| Self::annotation(t, params)).collect())
+            }
+            Type::Param(name) => params.get(name).cloned().unwrap_or(Ty::INT),
+        }
+    }
+
+    /// The fully solved form of `ty`, with unconstrained variables defaulting to int.
+    fn to_type(&self, ty: &Ty) -> Type {
+        match self.resolve(ty) {
+            Ty::Var(_) => Type::INT,
+            Ty::Int(kind) => Type::Int(kind),
+            Ty::Bool => Type::Bool,
+            Ty::Str => Type::Str,
+            Ty::Array(elem) => Type::Array(Box::new(self.to_type(&elem))),
+            Ty::List(elem) => Type::List(Box::new(self.to_type(&elem))),
+            Ty::Tuple(elems) => Type::Tuple(elems.iter().map(|t| self.to_type(t)).collect()),
+        }
+    }
+
+    /// Follow solved variables until reaching a shape or an unsolved variable.
+    fn shallow(&self, ty: &Ty) -> Ty {
+        let mut ty = ty.clone();
+        while let Ty::Var(v) = ty {
+            match &self.subst[v] {
+                Some(solved) => ty = solved.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// Substitute every solved variable in `ty`.
+    fn resolve(&self, ty: &Ty) -> Ty {
+        match self.shallow(ty) {
+            Ty::Array(elem) => Ty::Array(Box::new(self.resolve(&elem))),
+            Ty::List(elem) => Ty::List(Box::new(self.resolve(&elem))),
+            Ty::Tuple(elems) => Ty::Tuple(elems.iter().map(|t| self.resolve(t)).collect()),
+            other => other,
+        }
+    }
+
+    fn occurs(&self, var: usize, ty: &Ty) -> bool {
+        match self.shallow(ty) {
+            Ty::Var(v) => v == var,
+            Ty::Array(elem) | Ty::List(elem) => self.occurs(var, &elem),
+            Ty::Tuple(elems) => elems.iter().any(|t| self.occurs(var, t)),
+            Ty::Int(_) | Ty::Bool | Ty::Str => false,
+        }
+    }
+
+    fn unify(&mut self, expected: &Ty, found: &Ty, context: &str) -> Result<(), CompileError> {
+        if self.unify_types(expected, found) {
+            Ok(())
+        } else {
+            Err(self.error(
+                context,
+                &format!(
+                    "expected {}, found {}",
+                    self.resolve(expected),
+                    self.resolve(found)
+                ),
+            ))
+        }
+    }
+
+    fn unify_types(&mut self, a: &Ty, b: &Ty) -> bool {
+        let a = self.shallow(a);
+        let b = self.shallow(b);
+        match (&a, &b) {
+            (Ty::Var(x), Ty::Var(y)) if x == y => true,
+            (Ty::Var(v), other) | (other, Ty::Var(v)) => {
+                if self.occurs(*v, other) {
+                    return false;
+                }
+                self.subst[*v] = Some(other.clone());
+                true
+            }
+            (Ty::Array(x), Ty::Array(y)) | (Ty::List(x), Ty::List(y)) => self.unify_types(x, y),
+            (Ty::Tuple(xs), Ty::Tuple(ys)) => {
+                xs.len() == ys.len() && xs.iter().zip(ys).all(|(x, y)| self.unify_types(x, y))
+            }
+            _ => a == b,
+        }
+    }
+
+    fn error(&self, context: &str, msg: &str) -> CompileError {
+        CompileError::Type(format!("{} in {}: {}", context, self.scope, msg))
+    }
+
+    /// Give a function a signature up front, so calls can be checked in any order.
+    fn declare_function(&mut self, func: &Function) {
+        let mut type_params = Vec::new();
+        let mut params_by_name = HashMap::new();
+        for name in &func.type_params {
+            let var = self.fresh();
+            if let Ty::Var(v) = var {
+                type_params.push(v);
+            }
+            params_by_name.insert(name.clone(), var);
+        }
+        let generic = !func.type_params.is_empty();
+        let mut params = Vec::new();
+        for param in &func.params {
+            let ty = match &param.ty {
+                Some(ty) => Self::annotation(ty, &params_by_name),
+                // Unannotated parameters of a generic function are plain ints
+                None if generic => Ty::INT,
+                None => self.fresh(),
+            };
+            params.push(ty);
+        }
+        let ret = match &func.return_type {
+            Some(ty) => Self::annotation(ty, &params_by_name),
+            None if generic => Ty::INT,
+            None => self.fresh(),
+        };
+        self.functions.insert(
+            func.name.clone(),
+            Signature {
+                type_params,
+                params,
+                ret,
+            },
+        );
+    }
+
+    /// Take the types recorded so far, solved, to write them back.
+    fn annotator(&mut self) -> Annotator {
+        let exprs = std::mem::take(&mut self.expr_types);
+        let decls = std::mem::take(&mut self.decl_types);
+        let type_args = std::mem::take(&mut self.type_args);
+        let solve = |tys: &[Ty]| -> Vec<Type> { tys.iter().map(|ty| self.to_type(ty)).collect() };
+        Annotator {
+            exprs: solve(&exprs).into_iter(),
+            decls: solve(&decls).into_iter(),
+            type_args: type_args
+                .iter()
+                .map(|args| solve(args))
+                .collect::<Vec<_>>()
+                .into_iter(),
+        }
+    }
+
+    fn check_function(&mut self, func: &Function) -> Result<(), CompileError> {
+        self.scope = format!("function {}", func.name);
+        self.locals.clear();
+        let sig = &self.functions[&func.name];
+        let (params, ret) = (sig.params.clone(), sig.ret.clone());
+        for (param, ty) in func.params.iter().zip(params) {
+            self.bind_pattern(&param.pattern, ty)?;
+        }
+        self.return_ty = ret;
+        self.check_block(&func.body)
+    }
+
+    fn check_block(&mut self, stmts: &[Statement]) -> Result<(), CompileError> {
+        for stmt in stmts {
+            self.check_statement(stmt)?;
+        }
+        Ok(())
+    }
+
+    /// Check a block whose declarations go out of scope at its end.
+    fn check_nested_block(&mut self, stmts: &[Statement]) -> Result<(), CompileError> {
+        let outer = self.locals.clone();
+        let result = self.check_block(stmts);
+        self.locals = outer;
+        result
+    }
+
+    fn check_statement(&mut self, stmt: &Statement) -> Result<(), CompileError> {
+        match stmt {
+            Statement::VarDecl {
+                name,
+                ty,
+                expr: None,
+                ..
+            } => {
+                // The first assignment decides the type
+                let found = match ty {
+                    Some(annotated) => Self::annotation(annotated, &HashMap::new()),
+                    None => self.fresh(),
+                };
+                self.decl_types.push(found.clone());
+                self.locals.insert(name.clone(), found);
+            }
+            Statement::VarDecl {
+                name,
+                ty,
+                expr: Some(expr),
+                ..
+            }
+            | Statement::LetDecl { name, ty, expr, .. } => {
+                let found = self.infer(expr)?;
+                if let Some(annotated) = ty {
+                    let annotated = Self::annotation(annotated, &HashMap::new());
+                    self.unify(&annotated, &found, &format!("declaration of {}", name))?;
+                }
+                self.decl_types.push(found.clone());
+                self.locals.insert(name.clone(), found);
+            }
+            Statement::LetPattern { pattern, expr, .. } => {
+                let ty = self.infer(expr)?;
+                self.bind_pattern(pattern, ty)?;
+            }
+            Statement::Assign { name, expr, .. } => {
+                let found = self.infer(expr)?;
+                let var = self.lookup(name)?;
+                self.unify(&var, &found, &format!("assignment to {}", name))?;
+            }
+            Statement::IndexedAssign {
+                array, index, expr, ..
+            } => {
+                let container = self.infer(array)?;
+                let idx = self.infer(index)?;
+                self.require_integer(idx);
+                let elem = self.infer(expr)?;
+                self.checks.push(Check::Index {
+                    container,
+                    elem,
+                    writable: true,
+                    scope: self.scope.clone(),
+                });
+            }
+            Statement::Return { expr, .. } => {
+                let found = self.infer(expr)?;
+                let expected = self.return_ty.clone();
+                self.unify(&expected, &found, "return value")?;
+            }
+            Statement::Print { expr } => {
+                let ty = self.infer(expr)?;
+                self.checks.push(Check::Print {
+                    ty,
+                    scope: self.scope.clone(),
+                });
+            }
+            Statement::If {
+                cond,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                let ty = self.infer(cond)?;
+                self.unify(&Ty::Bool, &ty, "condition of if")?;
+                self.check_nested_block(then_branch)?;
+                if let Some(else_branch) = else_branch {
+                    self.check_nested_block(else_branch)?;
+                }
+            }
+            Statement::While { cond, body, .. } => {
+                let ty = self.infer(cond)?;
+                self.unify(&Ty::Bool, &ty, "condition of while")?;
+                self.check_nested_block(body)?;
+            }
+            Statement::ExprStmt(expr) => {
+                self.infer(expr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn bind_pattern(&mut self, pattern: &Pattern, ty: Ty) -> Result<(), CompileError> {
+        match pattern {
+            Pattern::Ident(name) => {
+                self.locals.insert(name.clone(), ty);
+            }
+            Pattern::Wildcard => {}
+            Pattern::Tuple(elems) => {
+                let elem_tys: Vec<Ty> = elems.iter().map(|_| self.fresh()).collect();
+                self.unify(&Ty::Tuple(elem_tys.clone()), &ty, "tuple pattern")?;
+                for (elem, elem_ty) in elems.iter().zip(elem_tys) {
+                    self.bind_pattern(elem, elem_ty)?;
+                }
+            }
+            Pattern::Array(elems) => {
+                let elem_ty = self.fresh();
+                self.unify(&Ty::Array(Box::new(elem_ty.clone())), &ty, "array pattern")?;
+                for elem in elems {
+                    self.bind_pattern(elem, elem_ty.clone())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Ty, CompileError> {
+        self.locals
+            .get(name)
+            .cloned()
+            .ok_or_else(|| self.error(name, "undefined variable"))
+    }
+
+    /// Infer the type of `expr`, recording it to be written back.
+    fn infer(&mut self, expr: &Expr) -> Result<Ty, CompileError> {
+        let ty = self.infer_kind(&expr.kind)?;
+        self.expr_types.push(ty.clone());
+        Ok(ty)
+    }
+
+    fn infer_kind(&mut self, kind: &ExprKind) -> Result<Ty, CompileError> {
+        match kind {
+            ExprKind::Number(..) => {
+                // Literals take whatever integer type the context needs, `int` by default
+                let ty = self.fresh();
+                self.require_integer(ty.clone());
+                Ok(ty)
+            }
+            ExprKind::Bool(_) => Ok(Ty::Bool),
+            ExprKind::StrLiteral(_) => Ok(Ty::Str),
+            ExprKind::Variable(name) => self.lookup(name),
+            ExprKind::Unary { op, expr, .. } => {
+                let ty = self.infer(expr)?;
+                match op {
+                    UnOp::Pos | UnOp::Neg => self.require_integer(ty.clone()),
+                    UnOp::Not => self.unify(&Ty::Bool, &ty, &format!("operand of {:?}", op))?,
+                }
+                Ok(ty)
+            }
+            ExprKind::Cast { expr, to } => {
+                let from = self.infer(expr)?;
+                self.checks.push(Check::Cast {
+                    from,
+                    scope: self.scope.clone(),
+                });
+                Ok(Ty::Int(*to))
+            }
+            ExprKind::Binary {
+                op, left, right, ..
+            } => {
+                let l = self.infer(left)?;
+                let r = self.infer(right)?;
+                let context = format!("operands of {:?}", op);
+                match op {
+                    BinOp::And | BinOp::Or => {
+                        self.unify(&Ty::Bool, &l, &context)?;
+                        self.unify(&Ty::Bool, &r, &context)?;
+                        Ok(Ty::Bool)
+                    }
+                    _ => {
+                        self.unify(&l, &r, &context)?;
+                        self.checks.push(Check::Operator {
+                            op: *op,
+                            operand: l.clone(),
+                            scope: self.scope.clone(),
+                        });
+                        if matches!(
+                            op,
+                            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem
+                        ) {
+                            Ok(l)
+                        } else {
+                            Ok(Ty::Bool)
+                        }
+                    }
+                }
+            }
+            ExprKind::Call { name, args } => {
+                let (ty, type_args) = self.infer_call(name, args)?;
+                if !type_args.is_empty() {
+                    self.calls.push(PendingCall {
+                        name: name.clone(),
+                        type_args: type_args.clone(),
+                        depth: self.depth,
+                    });
+                }
+                self.type_args.push(type_args);
+                Ok(ty)
+            }
+            ExprKind::ArrayLiteral(elems) => {
+                let elem_ty = self.fresh();
+                for elem in elems {
+                    let ty = self.infer(elem)?;
+                    self.unify(&elem_ty, &ty, "array element")?;
+                }
+                Ok(Ty::Array(Box::new(elem_ty)))
+            }
+            ExprKind::Tuple(elems) => {
+                let mut tys = Vec::new();
+                for elem in elems {
+                    tys.push(self.infer(elem)?);
+                }
+                Ok(Ty::Tuple(tys))
+            }
+            ExprKind::Index { array, index, .. } => {
+                let container = self.infer(array)?;
+                let idx = self.infer(index)?;
+                self.require_integer(idx);
+                let elem = self.fresh();
+                self.checks.push(Check::Index {
+                    container,
+                    elem: elem.clone(),
+                    writable: false,
+                    scope: self.scope.clone(),
+                });
+                Ok(elem)
+            }
+            ExprKind::Length { array } => {
+                let container = self.infer(array)?;
+                self.checks.push(Check::Length {
+                    container,
+                    scope: self.scope.clone(),
+                });
+                Ok(Ty::INT)
+            }
+            ExprKind::MethodCall {
+                receiver,
+                method,
+                args,
+                ..
+            } => {
+                let list = self.infer(receiver)?;
+                let elem = self.fresh();
+                let context = format!("receiver of .{}()", method);
+                self.unify(&Ty::List(Box::new(elem.clone())), &list, &context)?;
+                let (params, ret) = match method.as_str() {
+                    "push" => (vec![elem], Ty::INT),
+                    "pop" => (vec![], elem),
+                    "insert" => (vec![Ty::INT, elem], Ty::INT),
+                    "remove" => (vec![Ty::INT], elem),
+                    "clear" => (vec![], Ty::INT),
+                    _ => return Err(self.error(&context, "lists have no such method")),
+                };
+                self.check_args(&format!(".{}()", method), &params, args)?;
+                Ok(ret)
+            }
+        }
+    }
+
+    /// The type of a call's result, and the type arguments it passes if the
+    /// function is generic.
+    fn infer_call(&mut self, name: &str, args: &[Expr]) -> Result<(Ty, Vec<Ty>), CompileError> {
+        let mut type_args = Vec::new();
+        let (params, ret) = match name {
+            "length" => {
+                let [array] = args else {
+                    return Err(self.error(name, "takes exactly one argument"));
+                };
+                let container = self.infer(array)?;
+                self.checks.push(Check::Length {
+                    container,
+                    scope: self.scope.clone(),
+                });
+                return Ok((Ty::INT, type_args));
+            }
+            "list" => (vec![], Ty::List(Box::new(self.fresh()))),
+            "to_string" => {
+                let n = self.fresh();
+                self.require_integer(n.clone());
+                (vec![n], Ty::Str)
+            }
+            "parse_int" => (vec![Ty::Str], Ty::INT),
+            "wrapping_add" | "wrapping_sub" | "wrapping_mul" => {
+                let n = self.fresh();
+                self.require_integer(n.clone());
+                (vec![n.clone(), n.clone()], n)
+            }
+            _ => {
+                let sig = self
+                    .functions
+                    .get(name)
+                    .ok_or_else(|| self.error(name, "unknown function"))?;
+                let (type_params, params, ret) =
+                    (sig.type_params.clone(), sig.params.clone(), sig.ret.clone());
+                // Each call of a generic function gets its own copy of the type parameters
+                let mut fresh = HashMap::new();
+                for v in type_params {
+                    let var = self.fresh();
+                    type_args.push(var.clone());
+                    fresh.insert(v, var);
+                }
+                let params = params.iter().map(|t| self.instantiate(t, &fresh)).collect();
+                (params, self.instantiate(&ret, &fresh))
+            }
+        };
+        self.check_args(name, &params, args)?;
+        Ok((ret, type_args))
+    }
+
+    fn require_integer(&mut self, ty: Ty) {
+        self.checks.push(Check::Integer {
+            ty,
+            scope: self.scope.clone(),
+        });
+    }
+
+    fn instantiate(&self, ty: &Ty, fresh: &HashMap<usize, Ty>) -> Ty {
+        match ty {
+            Ty::Var(v) => fresh.get(v).cloned().unwrap_or(Ty::Var(*v)),
+            Ty::Array(elem) => Ty::Array(Box::new(self.instantiate(elem, fresh))),
+            Ty::List(elem) => Ty::List(Box::new(self.instantiate(elem, fresh))),
+            Ty::Tuple(elems) => {
+                Ty::Tuple(elems.iter().map(|t| self.instantiate(t, fresh)).collect())
+            }
+            other => other.clone(),
+        }
+    }
+
+    fn check_args(&mut self, name: &str, params: &[Ty], args: &[Expr]) -> Result<(), CompileError> {
+        if params.len() != args.len() {
+            return Err(self.error(
+                name,
+                &format!(
+                    "takes {} arguments but {} were given",
+                    params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        for (i, (param, arg)) in params.iter().zip(args).enumerate() {
+            let ty = self.infer(arg)?;
+            self.unify(param, &ty, &format!("argument {} of {}", i + 1, name))?;
+        }
+        Ok(())
+    }
+
+    /// Settle the deferred checks whose types are known, keeping the rest.
+    fn solve_checks(&mut self) -> Result<(), CompileError> {
+        loop {
+            let mut progress = false;
+            for check in std::mem::take(&mut self.checks) {
+                if self.try_check(&check)? {
+                    progress = true;
+                } else {
+                    self.checks.push(check);
+                }
+            }
+            if !progress {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Take the first generic call whose type arguments are fully known.
+    fn next_call(&mut self) -> Option<PendingCall> {
+        let i = self
+            .calls
+            .iter()
+            .position(|call| call.type_args.iter().all(|arg| self.is_solved(arg)))?;
+        Some(self.calls.remove(i))
+    }
+
+    fn is_solved(&self, ty: &Ty) -> bool {
+        match self.shallow(ty) {
+            Ty::Var(_) => false,
+            Ty::Array(elem) | Ty::List(elem) => self.is_solved(&elem),
+            Ty::Tuple(elems) => elems.iter().all(|t| self.is_solved(t)),
+            Ty::Int(_) | Ty::Bool | Ty::Str => true,
+        }
+    }
+
+    /// Make every unsolved variable in `ty` an int.
+    fn default_vars(&mut self, ty: &Ty) {
+        match self.shallow(ty) {
+            Ty::Var(_) => {
+                self.unify_types(ty, &Ty::INT);
+            }
+            Ty::Array(elem) | Ty::List(elem) => self.default_vars(&elem),
+            Ty::Tuple(elems) => {
+                for t in &elems {
+                    self.default_vars(t);
+                }
+            }
+            Ty::Int(_) | Ty::Bool | Ty::Str => {}
+        }
+    }
+
+    /// Apply a check whose type is known. Returns false if it still is not.
+    fn try_check(&mut self, check: &Check) -> Result<bool, CompileError> {
+        let (ty, scope) = match check {
+            Check::Index {
+                container, scope, ..
+            }
+            | Check::Length { container, scope } => (self.shallow(container), scope),
+            Check::Operator { operand, scope, .. } => (self.shallow(operand), scope),
+            Check::Print { ty, scope } | Check::Integer { ty, scope } => (self.shallow(ty), scope),
+            Check::Cast { from, scope } => (self.shallow(from), scope),
+        };
+        if let Ty::Var(_) = ty {
+            return Ok(false);
+        }
+        self.scope = scope.clone();
+        match check {
+            Check::Index { elem, writable, .. } => match &ty {
+                Ty::Array(inner) | Ty::List(inner) => self.unify(inner, elem, "element")?,
+                Ty::Str if *writable => {
+                    return Err(self.error("indexed assignment", "strings cannot be modified"));
+                }
+                Ty::Str => self.unify(&Ty::Str, elem, "element")?,
+                _ => return Err(self.error("index", &format!("cannot index into {}", ty))),
+            },
+            Check::Length { .. } => {
+                if !matches!(ty, Ty::Array(_) | Ty::List(_) | Ty::Str) {
+                    return Err(self.error("length", &format!("{} has no length", ty)));
+                }
+            }
+            Check::Operator { op, .. } => {
+                let supported = match op {
+                    BinOp::Add | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+                        matches!(ty, Ty::Int(_) | Ty::Str)
+                    }
+                    BinOp::Eq | BinOp::Ne => matches!(ty, Ty::Int(_) | Ty::Bool | Ty::Str),
+                    BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::Rem => {
+                        matches!(ty, Ty::Int(_))
+                    }
+                    BinOp::And | BinOp::Or => ty == Ty::Bool,
+                };
+                if !supported {
+                    return Err(self.error(
+                        &format!("operator {:?}", op),
+                        &format!("not supported for {}", self.resolve(&ty)),
+                    ));
+                }
+            }
+            Check::Print { .. } => {
+                if !matches!(ty, Ty::Int(_) | Ty::Bool | Ty::Str) {
+                    return Err(self.error("print", &format!("cannot print {}", self.resolve(&ty))));
+                }
+            }
+            Check::Integer { .. } => {
+                if !matches!(ty, Ty::Int(_)) {
+                    return Err(self.error(
+                        "integer",
+                        &format!("expected an integer type, found {}", self.resolve(&ty)),
+                    ));
+                }
+            }
+            Check::Cast { .. } => {
+                if !matches!(ty, Ty::Int(_) | Ty::Bool) {
+                    return Err(self.error("as", &format!("cannot cast {}", self.resolve(&ty))));
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Pin down the type of a check nothing else constrains: indexing and
+    /// `length` mean an array, everything else an `int`.
+    fn default_check(&mut self, check: &Check) -> Result<(), CompileError> {
+        let (ty, default, context, scope) = match check {
+            Check::Index {
+                container,
+                elem,
+                scope,
+                ..
+            } => (container, Ty::Array(Box::new(elem.clone())), "index", scope),
+            Check::Length { container, scope } => (
+                container,
+                Ty::Array(Box::new(self.fresh())),
+                "length",
+                scope,
+            ),
+            Check::Operator {
+                operand: ty, scope, ..
+            } => (ty, Ty::INT, "operator", scope),
+            Check::Print { ty, scope } => (ty, Ty::INT, "print", scope),
+            Check::Integer { ty, scope } => (ty, Ty::INT, "integer", scope),
+            Check::Cast { from: ty, scope } => (ty, Ty::INT, "as", scope),
+        };
+        if self.unify_types(ty, &default) {
+            return Ok(());
+        }
+        // `x = x[0]` makes `x` an array of itself
+        self.scope = scope.clone();
+        Err(self.error(
+            context,
+            &format!(
+                "{} would have to be {}, an infinite type",
+                self.resolve(ty),
+                self.resolve(&default)
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex;
+    use crate::parser::Parser;
+
+    fn checked(src: &str) -> Result<Program, CompileError> {
+        let mut prog = Parser::new(lex(src)?).parse_program()?;
+        check(&mut prog)?;
+        Ok(prog)
+    }
+
+    fn type_error(src: &str) -> String {
+        match checked(src) {
+            Err(CompileError::Type(msg)) => msg,
+            Err(e) => panic!("expected a type error, got {}", e),
+            Ok(_) => panic!("expected a type error"),
+        }
+    }
+
+    fn list_of(elem: Type) -> Type {
+        Type::List(Box::new(elem))
+    }
+
+    #[test]
+    fn declarations_take_the_type_of_later_uses() {
+        let prog = checked("var ys = list(); ys.push(\"b\");").unwrap();
+        let Statement::VarDecl { ty, .. } = &prog.statements[0] else {
+            panic!("expected a var");
+        };
+        assert_eq!(ty, &Some(list_of(Type::Str)));
+    }
+
+    #[test]
+    fn every_expression_is_annotated() {
+        let prog = checked("var ys = list(); ys.push(\"b\"); ys = list();").unwrap();
+        let Statement::Assign { expr, .. } = &prog.statements[2] else {
+            panic!("expected an assignment");
+        };
+        assert_eq!(expr.ty, Some(list_of(Type::Str)));
+        let Statement::ExprStmt(push) = &prog.statements[1] else {
+            panic!("expected a push");
+        };
+        let ExprKind::MethodCall { receiver, args, .. } = &push.kind else {
+            panic!("expected a method call");
+        };
+        assert_eq!(receiver.ty, Some(list_of(Type::Str)));
+        assert_eq!(args[0].ty, Some(Type::Str));
+    }
+
+    #[test]
+    fn arguments_take_the_parameter_type() {
+        let prog = checked(
+            "fn count(xs: list<string>) -> int { return length(xs); }
+             print(count(list()));",
+        )
+        .unwrap();
+        let Statement::Print { expr } = &prog.statements[0] else {
+            panic!("expected a print");
+        };
+        let ExprKind::Call { args, .. } = &expr.kind else {
+            panic!("expected a call");
+        };
+        assert_eq!(args[0].ty, Some(list_of(Type::Str)));
+    }
+
+    #[test]
+    fn literals_take_the_type_of_their_context() {
+        let prog = checked("let x: u8 = 200; print(x + 1);").unwrap();
+        let Statement::Print { expr } = &prog.statements[1] else {
+            panic!("expected a print");
+        };
+        let ExprKind::Binary { right, .. } = &expr.kind else {
+            panic!("expected a sum");
+        };
+        assert!(matches!(right.kind, ExprKind::Number(1, IntKind::U8)));
+        assert_eq!(expr.ty, Some(Type::Int(IntKind::U8)));
+    }
+
+    #[test]
+    fn unconstrained_types_default_to_int() {
+        let prog = checked("fn f(x) { return x; } var ys = list();").unwrap();
+        let f = &prog.functions[0];
+        assert_eq!(f.params[0].ty, Some(Type::INT));
+        assert_eq!(f.return_type, Some(Type::INT));
+        let Statement::VarDecl { ty, .. } = &prog.statements[0] else {
+            panic!("expected a var");
+        };
+        assert_eq!(ty, &Some(list_of(Type::INT)));
+    }
+
+    #[test]
+    fn deferred_checks_wait_for_the_container_type() {
+        let prog = checked(
+            "fn first(xs) { return xs[0]; }
+             print(first(list()) + \"!\");",
+        )
+        .unwrap();
+        let first = &prog.functions[0];
+        assert_eq!(first.params[0].ty, Some(list_of(Type::Str)));
+        assert_eq!(first.return_type, Some(Type::Str));
+    }
+
+    #[test]
+    fn indexing_defaults_to_an_array() {
+        let prog = checked("fn first(xs) { return xs[0]; }").unwrap();
+        let first = &prog.functions[0];
+        assert_eq!(first.params[0].ty, Some(Type::Array(Box::new(Type::INT))));
+    }
+
+    #[test]
+    fn infinite_types_are_reported_when_defaulting() {
+        let msg = type_error("fn f(x) { x = x[0]; return 0; }");
+        assert!(msg.contains("infinite type"), "{}", msg);
+    }
+
+    #[test]
+    fn length_accepts_arrays_lists_and_strings() {
+        checked("print(length([1, 2])); print(length(\"ab\")); print(length(list()));").unwrap();
+        let msg = type_error("print(length(5));");
+        assert!(msg.contains("int has no length"), "{}", msg);
+    }
+
+    #[test]
+    fn deferred_checks_report_unsupported_operators() {
+        let msg = type_error("print(true - false);");
+        assert!(msg.contains("not supported for bool"), "{}", msg);
+        let msg = type_error("let s = \"ab\"; s[0] = \"c\";");
+        assert!(msg.contains("strings cannot be modified"), "{}", msg);
+    }
+
+    #[test]
+    fn generic_functions_are_instantiated_per_type() {
+        let prog = checked(
+            "fn id<T>(x: T) -> T { return x; }
+             print(id(1)); print(id(\"a\")); print(id(2));",
+        )
+        .unwrap();
+        // `id(1)` waits for its literal to default to int
+        let names: Vec<&str> = prog.functions.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["id<string>", "id<int>"]);
+        assert_eq!(prog.functions[0].params[0].ty, Some(Type::Str));
+        let Statement::Print { expr } = &prog.statements[1] else {
+            panic!("expected a print");
+        };
+        let ExprKind::Call { name, .. } = &expr.kind else {
+            panic!("expected a call");
+        };
+        assert_eq!(name, "id<string>");
+    }
+
+    #[test]
+    fn instances_constrain_the_functions_they_call() {
+        let prog = checked(
+            "fn show(s) { print(s); return 0; }
+             fn f<T>(x: T) -> int { return show(x); }
+             print(f(\"hi\"));",
+        )
+        .unwrap();
+        assert_eq!(prog.functions[0].params[0].ty, Some(Type::Str));
+    }
+
+    #[test]
+    fn annotations_in_generic_bodies_are_substituted() {
+        let prog = checked(
+            "fn keep<T>(x: T) -> T { let tmp: T = x; return tmp; }
+             print(keep(\"a\"));",
+        )
+        .unwrap();
+        let Statement::LetDecl { ty, .. } = &prog.functions[0].body[0] else {
+            panic!("expected a let");
+        };
+        assert_eq!(ty, &Some(Type::Str));
+    }
+
+    #[test]
+    fn polymorphic_recursion_is_cut_off() {
+        let msg = type_error(
+            "fn f<T>(x: T) -> int { if (false) { return f((x, x)); } return 0; }
+             print(f(1));",
+        );
+        assert!(msg.contains("ever larger types"), "{}", msg);
+    }
+
+    #[test]
+    fn generic_bodies_are_checked_per_instance() {
+        let msg =
+            type_error("fn sub<T>(a: T, b: T) -> T { return a - b; } print(sub(\"a\", \"b\"));");
+        assert!(msg.contains("function sub<string>"), "{}", msg);
+    }
+}